@@ -0,0 +1,125 @@
+//! Erasure of the accounts whose deletion was requested, once their grace period is over. Deleting
+//! the user cascades to their tokens, passkeys, identities and sessions in Postgres.
+
+use diesel::PgConnection;
+use diesel::r2d2::{ConnectionManager, Pool};
+use crate::db::run_blocking;
+use crate::errors::{adapt_app_error, AppError};
+use crate::models::AccountDeletion;
+use crate::store::AppStore;
+
+/// Erase the accounts whose grace period is over, returning their number
+pub async fn erase_due_accounts(db: &Pool<ConnectionManager<PgConnection>>, store: &AppStore) -> Result<usize, AppError> {
+    use crate::schema::account_deletions::dsl::*;
+    use diesel::{dsl::now, prelude::*};
+
+    let due = run_blocking(db, |conn| {
+        account_deletions
+            .filter(canceled_at.is_null())
+            .filter(erased_at.is_null())
+            .filter(erase_after.le(now))
+            .select(AccountDeletion::as_select())
+            .load(conn)
+            .map_err(adapt_app_error)
+    }).await?;
+
+    let mut erased = 0;
+    for deletion in due {
+        let deletion_id = deletion.id;
+        let owner_id = deletion.user_id;
+        // the deletion may have been canceled in the meantime
+        let is_erased = run_blocking(db, move |conn| {
+            conn.transaction(|conn| {
+                let updated = diesel::update(account_deletions
+                    .find(deletion_id)
+                    .filter(canceled_at.is_null())
+                    .filter(erased_at.is_null()))
+                    .set(erased_at.eq(now))
+                    .execute(conn)?;
+                if updated > 0 {
+                    diesel::delete(crate::schema::users::table.find(owner_id)).execute(conn)?;
+                }
+                Ok(updated > 0)
+            }).map_err(|e: diesel::result::Error| adapt_app_error(e))
+        }).await?;
+
+        if is_erased {
+            // sessions stored in Redis, or cached, are not deleted with the user
+            store.delete_user_sessions(owner_id, None).await.map_err(adapt_app_error)?;
+            tracing::info!(user_id = owner_id, "account erased");
+            erased += 1;
+        }
+    }
+
+    Ok(erased)
+}
+
+/// Periodically erase the accounts whose grace period is over
+pub async fn continuously_erase_accounts(db: Pool<ConnectionManager<PgConnection>>, store: AppStore, period: std::time::Duration) {
+    let mut interval = tokio::time::interval(period);
+    loop {
+        interval.tick().await;
+        if let Err(e) = erase_due_accounts(&db, &store).await {
+            tracing::warn!("failed to erase accounts: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use diesel::prelude::*;
+    use futures::FutureExt;
+    use crate::Config;
+    use crate::auth::Backend;
+    use crate::db::TestDb;
+    use crate::models::User;
+    use crate::store::PgStore;
+    use crate::webauthn::Webauthn;
+    use super::*;
+
+    fn get_db_pool() -> TestDb {
+        let config = Config::new().unwrap();
+        TestDb::new(&config)
+    }
+
+    fn find_user(conn: &mut PgConnection, address: &str) -> Option<User> {
+        use crate::schema::users::dsl::*;
+
+        users.filter(email.eq(address)).select(User::as_select()).first(conn).optional().unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_erase_due_accounts() {
+        let db = get_db_pool();
+        db.run_test(|pool| async move {
+            let backend = Backend::new(pool.clone(), Webauthn::new("localhost", "Anthère", "http://localhost:7878"));
+            let store = AppStore::Postgres(PgStore::new(pool.clone()));
+            let conn = &mut pool.get().unwrap();
+            let miles = find_user(conn, "miles.davis@trumpet.com").unwrap();
+            let marcus = find_user(conn, "marcus.miller@bass.com").unwrap();
+
+            // due right away
+            let deletion = backend.schedule_account_deletion(&miles, chrono::TimeDelta::zero()).await.unwrap();
+            assert!(matches!(
+                backend.schedule_account_deletion(&miles, chrono::TimeDelta::zero()).await,
+                Err(AppError::Conflict(_))
+            ));
+            backend.schedule_account_deletion(&marcus, chrono::TimeDelta::days(30)).await.unwrap();
+
+            assert_eq!(erase_due_accounts(&pool, &store).await.unwrap(), 1);
+            assert!(find_user(conn, "miles.davis@trumpet.com").is_none());
+            assert!(find_user(conn, "marcus.miller@bass.com").is_some());
+
+            // the audit record remains
+            let record: AccountDeletion = {
+                use crate::schema::account_deletions::dsl::*;
+                account_deletions.find(deletion.id).select(AccountDeletion::as_select()).first(conn).unwrap()
+            };
+            assert!(record.erased_at.is_some());
+
+            assert!(backend.cancel_account_deletion(marcus.id).await.unwrap());
+            assert!(!backend.cancel_account_deletion(marcus.id).await.unwrap());
+            assert_eq!(erase_due_accounts(&pool, &store).await.unwrap(), 0);
+        }.boxed()).await;
+    }
+}
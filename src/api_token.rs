@@ -0,0 +1,53 @@
+//! Personal access tokens, letting scripts call the API without a browser session.
+//! Only a SHA-256 hash of the token is stored: tokens are random, so a slow password hash is not needed.
+
+use std::{fmt, str::FromStr};
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+// makes leaked tokens easy to recognize by secret scanners
+const TOKEN_PREFIX: &str = "anthere_pat_";
+const TOKEN_LENGTH: usize = 32;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+    Read,
+    Write,
+    Import,
+}
+
+impl fmt::Display for Scope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Scope::Read => write!(f, "read"),
+            Scope::Write => write!(f, "write"),
+            Scope::Import => write!(f, "import"),
+        }
+    }
+}
+
+impl FromStr for Scope {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "read" => Ok(Scope::Read),
+            "write" => Ok(Scope::Write),
+            "import" => Ok(Scope::Import),
+            _ => Err("Unknown token scope"),
+        }
+    }
+}
+
+pub fn generate_token() -> String {
+    let mut bytes = [0u8; TOKEN_LENGTH];
+    rand::rngs::OsRng.fill_bytes(&mut bytes);
+    format!("{}{}", TOKEN_PREFIX, URL_SAFE_NO_PAD.encode(bytes))
+}
+
+pub fn hash_token(token: &str) -> Vec<u8> {
+    Sha256::digest(token.as_bytes()).to_vec()
+}
\ No newline at end of file
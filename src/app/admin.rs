@@ -0,0 +1,407 @@
+//! This module contains the admin area, restricted to users with the `ManageUsers` permission
+
+use axum::{extract::{FromRef, Path, State}, Json, Router, routing::{delete, get, post}};
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use diesel::PgConnection;
+use diesel::r2d2::{ConnectionManager, Pool};
+use serde::Serialize;
+use crate::auth::AuthSession;
+use crate::db::run_blocking;
+use crate::errors::{adapt_app_error, AppError};
+use crate::models::{AccountDeletion, Role, User};
+use crate::store::AppStore;
+
+#[derive(Clone)]
+struct AdminState {
+    db: Pool<ConnectionManager<PgConnection>>,
+    store: AppStore,
+}
+
+impl FromRef<AdminState> for Pool<ConnectionManager<PgConnection>> {
+    fn from_ref(state: &AdminState) -> Self {
+        state.db.clone()
+    }
+}
+
+impl FromRef<AdminState> for AppStore {
+    fn from_ref(state: &AdminState) -> Self {
+        state.store.clone()
+    }
+}
+
+pub fn router(db: Pool<ConnectionManager<PgConnection>>, store: AppStore) -> Router<()> {
+    Router::new()
+        .route("/admin/users", get(list_users))
+        .route("/admin/users/:id/confirm", post(confirm_user))
+        .route("/admin/users/:id/lock", post(lock_user))
+        .route("/admin/users/:id/unlock", post(unlock_user))
+        .route("/admin/users/:id/sessions", delete(revoke_user_sessions))
+        .route("/admin/account-deletions", get(list_account_deletions))
+        .route("/admin/storage", get(storage_usage))
+        .route("/admin/sessions/cache", get(session_cache_stats))
+        .with_state(AdminState { db, store })
+}
+
+#[derive(Serialize)]
+struct AdminUser {
+    id: i32,
+    email: String,
+    role: Role,
+    sign_in_count: i32,
+    last_sign_in_at: Option<chrono::NaiveDateTime>,
+    confirmed_at: Option<chrono::NaiveDateTime>,
+    locked_at: Option<chrono::NaiveDateTime>,
+    created_at: chrono::NaiveDateTime,
+}
+
+impl From<User> for AdminUser {
+    fn from(user: User) -> Self {
+        AdminUser {
+            id: user.id,
+            role: user.role(),
+            email: user.email,
+            sign_in_count: user.sign_in_count,
+            last_sign_in_at: user.last_sign_in_at,
+            confirmed_at: user.confirmed_at,
+            locked_at: user.locked_at,
+            created_at: user.created_at,
+        }
+    }
+}
+
+async fn list_users(State(db): State<Pool<ConnectionManager<PgConnection>>>) -> Result<impl IntoResponse, AppError> {
+    use crate::schema::users::dsl::*;
+    use diesel::prelude::*;
+
+    let all_users = run_blocking(&db, |conn| {
+        users.select(User::as_select()).order(id.asc()).load(conn).map_err(adapt_app_error)
+    }).await?;
+
+    Ok(Json(all_users.into_iter().map(AdminUser::from).collect::<Vec<_>>()))
+}
+
+async fn confirm_user(
+    State(db): State<Pool<ConnectionManager<PgConnection>>>,
+    Path(user_id): Path<i32>,
+) -> Result<Json<AdminUser>, AppError> {
+    use crate::schema::users::dsl::*;
+    use diesel::{dsl::now, prelude::*};
+
+    let user = run_blocking(&db, move |conn| {
+        let updated = diesel::update(users.find(user_id).filter(confirmed_at.is_null()))
+            .set(confirmed_at.eq(now))
+            .returning(User::as_returning())
+            .get_result(conn)
+            .optional();
+
+        match updated {
+            // already confirmed, keep the original confirmation date
+            Ok(None) => users.find(user_id).select(User::as_select()).first(conn).optional(),
+            updated => updated,
+        }.map_err(adapt_app_error)
+    }).await;
+
+    user_response(user)
+}
+
+async fn lock_user(
+    State(db): State<Pool<ConnectionManager<PgConnection>>>,
+    auth_session: AuthSession,
+    Path(user_id): Path<i32>,
+) -> Result<Json<AdminUser>, AppError> {
+    use crate::schema::users::dsl::*;
+    use diesel::{dsl::now, prelude::*};
+
+    // admins cannot lock themselves out
+    if auth_session.user.map(|user| user.id) == Some(user_id) {
+        return Err(AppError::Conflict(String::from("you cannot lock yourself out")));
+    }
+
+    let user = run_blocking(&db, move |conn| {
+        let updated = diesel::update(users.find(user_id).filter(locked_at.is_null()))
+            .set(locked_at.eq(now))
+            .returning(User::as_returning())
+            .get_result(conn)
+            .optional();
+
+        match updated {
+            // already locked
+            Ok(None) => users.find(user_id).select(User::as_select()).first(conn).optional(),
+            updated => updated,
+        }.map_err(adapt_app_error)
+    }).await;
+
+    user_response(user)
+}
+
+async fn unlock_user(
+    State(db): State<Pool<ConnectionManager<PgConnection>>>,
+    Path(user_id): Path<i32>,
+) -> Result<Json<AdminUser>, AppError> {
+    use crate::schema::users::dsl::*;
+    use diesel::prelude::*;
+
+    let updated = run_blocking(&db, move |conn| {
+        diesel::update(users.find(user_id))
+            .set(locked_at.eq(None::<chrono::NaiveDateTime>))
+            .returning(User::as_returning())
+            .get_result(conn)
+            .optional()
+            .map_err(adapt_app_error)
+    }).await;
+
+    user_response(updated)
+}
+
+/// Log a user out everywhere, e.g. after a compromise
+async fn revoke_user_sessions(
+    State(store): State<AppStore>,
+    Path(user_id): Path<i32>,
+) -> Result<StatusCode, AppError> {
+    store.delete_user_sessions(user_id, None).await.map_err(adapt_app_error)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn session_cache_stats(State(store): State<AppStore>) -> Result<impl IntoResponse, AppError> {
+    store.cache_stats().map(Json).ok_or(AppError::NotFound)
+}
+
+fn user_response(user: Result<Option<User>, AppError>) -> Result<Json<AdminUser>, AppError> {
+    user?.map(|user| Json(AdminUser::from(user))).ok_or(AppError::NotFound)
+}
+
+#[derive(diesel::QueryableByName, Serialize)]
+struct StorageUsage {
+    #[diesel(sql_type = diesel::sql_types::Integer)]
+    user_id: i32,
+    #[diesel(sql_type = diesel::sql_types::Text)]
+    email: String,
+    #[diesel(sql_type = diesel::sql_types::BigInt)]
+    bytes: i64,
+}
+
+#[derive(Serialize)]
+struct AdminAccountDeletion {
+    id: i32,
+    user_id: i32,
+    requested_at: chrono::NaiveDateTime,
+    erase_after: chrono::NaiveDateTime,
+    canceled_at: Option<chrono::NaiveDateTime>,
+    erased_at: Option<chrono::NaiveDateTime>,
+}
+
+impl From<AccountDeletion> for AdminAccountDeletion {
+    fn from(deletion: AccountDeletion) -> Self {
+        AdminAccountDeletion {
+            id: deletion.id,
+            user_id: deletion.user_id,
+            requested_at: deletion.requested_at,
+            erase_after: deletion.erase_after,
+            canceled_at: deletion.canceled_at,
+            erased_at: deletion.erased_at,
+        }
+    }
+}
+
+/// The audit trail of account deletions, pending, canceled or done, the latest first
+async fn list_account_deletions(State(db): State<Pool<ConnectionManager<PgConnection>>>) -> Result<impl IntoResponse, AppError> {
+    use crate::schema::account_deletions::dsl::*;
+    use diesel::prelude::*;
+
+    let deletions = run_blocking(&db, |conn| {
+        account_deletions.select(AccountDeletion::as_select()).order(id.desc()).load(conn).map_err(adapt_app_error)
+    }).await?;
+
+    Ok(Json(deletions.into_iter().map(AdminAccountDeletion::from).collect::<Vec<_>>()))
+}
+
+/// Database storage used by each user, i.e. the size of all the rows they own
+async fn storage_usage(State(db): State<Pool<ConnectionManager<PgConnection>>>) -> Result<impl IntoResponse, AppError> {
+    use diesel::prelude::*;
+
+    let usage = run_blocking(&db, |conn| {
+        diesel::sql_query(STORAGE_USAGE_QUERY).load::<StorageUsage>(conn).map_err(adapt_app_error)
+    }).await?;
+
+    Ok(Json(usage))
+}
+
+// Tables holding user data must be added here
+const STORAGE_USAGE_QUERY: &str = "
+    SELECT u.id AS user_id, u.email, (
+        pg_column_size(u.*)
+        + COALESCE((SELECT SUM(pg_column_size(t.*)) FROM api_tokens t WHERE t.user_id = u.id), 0)
+        + COALESCE((SELECT SUM(pg_column_size(c.*)) FROM webauthn_credentials c WHERE c.user_id = u.id), 0)
+        + COALESCE((SELECT SUM(pg_column_size(i.*)) FROM oidc_identities i WHERE i.user_id = u.id), 0)
+        + COALESCE((SELECT SUM(pg_column_size(r.*)) FROM remember_tokens r WHERE r.user_id = u.id), 0)
+        + COALESCE((SELECT SUM(pg_column_size(s.*)) FROM sessions s WHERE s.user_id = u.id), 0)
+    )::BIGINT AS bytes
+    FROM users u
+    ORDER BY bytes DESC";
+
+#[cfg(test)]
+mod tests {
+    use axum::body::Body;
+    use axum::http::{header::{CONTENT_TYPE, COOKIE, SET_COOKIE}, Request};
+    use axum_login::{permission_required, AuthManagerLayerBuilder};
+    use diesel::prelude::*;
+    use futures::FutureExt;
+    use tower::ServiceExt;
+    use tower_sessions::SessionManagerLayer;
+    use crate::Config;
+    use crate::auth::{AuthCredentials, Backend, Credentials, Permission};
+    use crate::db::TestDb;
+    use crate::store::PgStore;
+    use crate::webauthn::Webauthn;
+    use super::*;
+
+    fn get_db_pool() -> TestDb {
+        let config = Config::new().unwrap();
+        TestDb::new(&config)
+    }
+
+    /// The admin area behind its permission check, and a route to log in
+    fn app(pool: Pool<ConnectionManager<PgConnection>>, store: AppStore) -> Router<()> {
+        let backend = Backend::new(pool.clone(), Webauthn::new("localhost", "Anthère", "http://localhost:7878"));
+        let session_layer = SessionManagerLayer::new(store.clone());
+
+        router(pool, store)
+            .route_layer(permission_required!(Backend, Permission::ManageUsers))
+            .route("/login", post(|mut auth_session: AuthSession, Json(creds): Json<Credentials>| async move {
+                let user = auth_session.authenticate(AuthCredentials::Password(creds)).await.unwrap().unwrap();
+                auth_session.login(&user).await.unwrap();
+            }))
+            .layer(AuthManagerLayerBuilder::new(backend, session_layer).build())
+    }
+
+    fn make_admin(conn: &mut PgConnection, address: &str) -> User {
+        use crate::schema::users::dsl::*;
+
+        diesel::update(users.filter(email.eq(address)))
+            .set(role.eq(Role::Admin.to_string()))
+            .returning(User::as_returning())
+            .get_result(conn)
+            .unwrap()
+    }
+
+    fn find_user(conn: &mut PgConnection, address: &str) -> User {
+        use crate::schema::users::dsl::*;
+
+        users.filter(email.eq(address)).select(User::as_select()).first(conn).unwrap()
+    }
+
+    /// Log in, returning the session cookie
+    async fn login(app: &Router<()>, email: &str, password: &str) -> String {
+        let body = serde_json::json!({ "email": email, "password": password }).to_string();
+        let request = Request::post("/login").header(CONTENT_TYPE, "application/json").body(Body::from(body)).unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let cookie = response.headers()[SET_COOKIE].to_str().unwrap();
+        cookie.split(';').next().unwrap().to_string()
+    }
+
+    async fn send(app: &Router<()>, cookie: &str, request: axum::http::request::Builder) -> (StatusCode, serde_json::Value) {
+        let request = request.header(COOKIE, cookie).body(Body::empty()).unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (status, serde_json::from_slice(&body).unwrap_or(serde_json::Value::Null))
+    }
+
+    #[tokio::test]
+    async fn test_requires_admin() {
+        let db = get_db_pool();
+        db.run_test(|pool| async move {
+            let app = app(pool.clone(), AppStore::Postgres(PgStore::new(pool)));
+
+            let (status, _) = send(&app, "", Request::get("/admin/users")).await;
+            assert_eq!(status, StatusCode::FORBIDDEN);
+
+            let cookie = login(&app, "marcus.miller@bass.com", "secr3t").await;
+            for request in [
+                Request::get("/admin/users"),
+                Request::post("/admin/users/1/lock"),
+                Request::delete("/admin/users/1/sessions"),
+                Request::get("/admin/storage"),
+            ] {
+                let (status, _) = send(&app, &cookie, request).await;
+                assert_eq!(status, StatusCode::FORBIDDEN);
+            }
+        }.boxed()).await;
+    }
+
+    #[tokio::test]
+    async fn test_manage_users() {
+        let db = get_db_pool();
+        db.run_test(|pool| async move {
+            let conn = &mut pool.get().unwrap();
+            let admin = make_admin(conn, "miles.davis@trumpet.com");
+            let marcus = find_user(conn, "marcus.miller@bass.com");
+            let app = app(pool.clone(), AppStore::Postgres(PgStore::new(pool.clone())));
+            let cookie = login(&app, "miles.davis@trumpet.com", "passw0rd").await;
+
+            let (status, body) = send(&app, &cookie, Request::get("/admin/users")).await;
+            assert_eq!(status, StatusCode::OK);
+            let emails = body.as_array().unwrap().iter().map(|user| user["email"].as_str().unwrap()).collect::<Vec<_>>();
+            assert_eq!(emails, vec!["miles.davis@trumpet.com", "marcus.miller@bass.com"]);
+            assert_eq!(body[0]["role"], "admin");
+
+            // confirming twice keeps the original date
+            let (status, confirmed) = send(&app, &cookie, Request::post(format!("/admin/users/{}/confirm", marcus.id))).await;
+            assert_eq!(status, StatusCode::OK);
+            assert!(!confirmed["confirmed_at"].is_null());
+            let (_, again) = send(&app, &cookie, Request::post(format!("/admin/users/{}/confirm", marcus.id))).await;
+            assert_eq!(again["confirmed_at"], confirmed["confirmed_at"]);
+            let (status, _) = send(&app, &cookie, Request::post("/admin/users/0/confirm")).await;
+            assert_eq!(status, StatusCode::NOT_FOUND);
+
+            let (status, locked) = send(&app, &cookie, Request::post(format!("/admin/users/{}/lock", marcus.id))).await;
+            assert_eq!(status, StatusCode::OK);
+            assert!(!locked["locked_at"].is_null());
+            let (status, _) = send(&app, &cookie, Request::post(format!("/admin/users/{}/lock", admin.id))).await;
+            assert_eq!(status, StatusCode::CONFLICT);
+            let (status, unlocked) = send(&app, &cookie, Request::post(format!("/admin/users/{}/unlock", marcus.id))).await;
+            assert_eq!(status, StatusCode::OK);
+            assert!(unlocked["locked_at"].is_null());
+            let (status, _) = send(&app, &cookie, Request::post("/admin/users/0/unlock")).await;
+            assert_eq!(status, StatusCode::NOT_FOUND);
+        }.boxed()).await;
+    }
+
+    #[tokio::test]
+    async fn test_sessions_and_storage() {
+        let db = get_db_pool();
+        db.run_test(|pool| async move {
+            let conn = &mut pool.get().unwrap();
+            make_admin(conn, "miles.davis@trumpet.com");
+            let marcus = find_user(conn, "marcus.miller@bass.com");
+            let store = AppStore::Postgres(PgStore::new(pool.clone()));
+            let app = app(pool.clone(), store.clone());
+            let cookie = login(&app, "miles.davis@trumpet.com", "passw0rd").await;
+
+            let marcus_bytes = |usage: &serde_json::Value| usage.as_array().unwrap().iter()
+                .find(|user| user["user_id"] == marcus.id)
+                .map(|user| user["bytes"].as_i64().unwrap())
+                .unwrap();
+            let (status, before) = send(&app, &cookie, Request::get("/admin/storage")).await;
+            assert_eq!(status, StatusCode::OK);
+            assert_eq!(before.as_array().unwrap().len(), 2);
+
+            // the sessions of a user count in their storage
+            login(&app, "marcus.miller@bass.com", "secr3t").await;
+            assert_eq!(store.user_sessions(marcus.id).await.unwrap().len(), 1);
+            let (_, after) = send(&app, &cookie, Request::get("/admin/storage")).await;
+            assert!(marcus_bytes(&after) > marcus_bytes(&before));
+
+            let (status, _) = send(&app, &cookie, Request::delete(format!("/admin/users/{}/sessions", marcus.id))).await;
+            assert_eq!(status, StatusCode::NO_CONTENT);
+            assert!(store.user_sessions(marcus.id).await.unwrap().is_empty());
+
+            // the cache statistics are only served when sessions are cached
+            let (status, _) = send(&app, &cookie, Request::get("/admin/sessions/cache")).await;
+            assert_eq!(status, StatusCode::NOT_FOUND);
+        }.boxed()).await;
+    }
+}
@@ -0,0 +1,260 @@
+use axum::{extract::State, Json, Router, routing::{get, post}};
+use axum::http::{header::CONTENT_DISPOSITION, StatusCode};
+use axum::response::IntoResponse;
+use serde::Serialize;
+use crate::api_token::Scope;
+use crate::app::api::ApiUser;
+use crate::auth::AuthSession;
+use crate::errors::{adapt_app_error, AppError};
+use crate::models::{ApiToken, OidcIdentity, Session, User, WebauthnCredential};
+use crate::store::AppStore;
+
+#[derive(Clone)]
+struct AccountState {
+    store: AppStore,
+    deletion_grace_period: chrono::TimeDelta,
+}
+
+pub fn router(store: AppStore, deletion_grace_period: chrono::TimeDelta) -> Router<()> {
+    Router::new()
+        .route("/api/account/deletion", post(request_deletion))
+        .route("/api/account/export", get(export_data))
+        .with_state(AccountState { store, deletion_grace_period })
+}
+
+#[derive(Serialize)]
+struct ScheduledDeletion {
+    erase_after: chrono::NaiveDateTime,
+}
+
+/// Schedule the erasure of the account and sign the user out everywhere, API tokens included:
+/// logging in again before the end of the grace period cancels it. Like sessions, it is only
+/// requested from a browser session.
+async fn request_deletion(
+    State(state): State<AccountState>,
+    api_user: ApiUser,
+    mut auth_session: AuthSession,
+) -> Result<(StatusCode, Json<ScheduledDeletion>), AppError> {
+    if !api_user.is_session() {
+        return Err(AppError::Forbidden);
+    }
+
+    // sign the user out first, so that a failure leaves no deletion scheduled behind a live session
+    state.store.delete_user_sessions(api_user.user.id, None).await.map_err(adapt_app_error)?;
+    auth_session.logout().await.map_err(adapt_app_error)?;
+    let deletion = auth_session.backend.schedule_account_deletion(&api_user.user, state.deletion_grace_period).await?;
+
+    Ok((StatusCode::ACCEPTED, Json(ScheduledDeletion { erase_after: deletion.erase_after })))
+}
+
+/// Everything stored about a user, secrets (password and token hashes, keys) excepted
+#[derive(Serialize)]
+struct Takeout {
+    exported_at: chrono::NaiveDateTime,
+    profile: Profile,
+    passkeys: Vec<TakeoutPasskey>,
+    api_tokens: Vec<TakeoutToken>,
+    identities: Vec<TakeoutIdentity>,
+    sessions: Vec<TakeoutSession>,
+}
+
+#[derive(Serialize)]
+struct Profile {
+    email: String,
+    role: String,
+    sign_in_count: i32,
+    current_sign_in_at: Option<chrono::NaiveDateTime>,
+    current_sign_in_ip: Option<String>,
+    last_sign_in_at: Option<chrono::NaiveDateTime>,
+    last_sign_in_ip: Option<String>,
+    confirmed_at: Option<chrono::NaiveDateTime>,
+    created_at: chrono::NaiveDateTime,
+}
+
+impl From<User> for Profile {
+    fn from(user: User) -> Self {
+        Profile {
+            role: user.role().to_string(),
+            email: user.email,
+            sign_in_count: user.sign_in_count,
+            current_sign_in_at: user.current_sign_in_at,
+            current_sign_in_ip: user.current_sign_in_ip.map(|ip| ip.addr().to_string()),
+            last_sign_in_at: user.last_sign_in_at,
+            last_sign_in_ip: user.last_sign_in_ip.map(|ip| ip.addr().to_string()),
+            confirmed_at: user.confirmed_at,
+            created_at: user.created_at,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct TakeoutPasskey {
+    name: String,
+    last_used_at: Option<chrono::NaiveDateTime>,
+    created_at: chrono::NaiveDateTime,
+}
+
+impl From<WebauthnCredential> for TakeoutPasskey {
+    fn from(credential: WebauthnCredential) -> Self {
+        TakeoutPasskey { name: credential.name, last_used_at: credential.last_used_at, created_at: credential.created_at }
+    }
+}
+
+#[derive(Serialize)]
+struct TakeoutToken {
+    name: String,
+    scopes: Vec<Scope>,
+    expires_at: Option<chrono::NaiveDateTime>,
+    last_used_at: Option<chrono::NaiveDateTime>,
+    created_at: chrono::NaiveDateTime,
+}
+
+impl From<ApiToken> for TakeoutToken {
+    fn from(token: ApiToken) -> Self {
+        TakeoutToken {
+            scopes: token.scopes(),
+            name: token.name,
+            expires_at: token.expires_at,
+            last_used_at: token.last_used_at,
+            created_at: token.created_at,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct TakeoutIdentity {
+    issuer: String,
+    subject: String,
+    created_at: chrono::NaiveDateTime,
+}
+
+impl From<OidcIdentity> for TakeoutIdentity {
+    fn from(identity: OidcIdentity) -> Self {
+        TakeoutIdentity { issuer: identity.issuer, subject: identity.subject, created_at: identity.created_at }
+    }
+}
+
+#[derive(Serialize)]
+struct TakeoutSession {
+    ip: Option<String>,
+    user_agent: Option<String>,
+    #[serde(with = "time::serde::rfc3339")]
+    created_at: time::OffsetDateTime,
+    #[serde(with = "time::serde::rfc3339")]
+    last_seen_at: time::OffsetDateTime,
+}
+
+impl From<Session> for TakeoutSession {
+    fn from(session: Session) -> Self {
+        TakeoutSession {
+            ip: session.ip.map(|ip| ip.addr().to_string()),
+            user_agent: session.user_agent,
+            created_at: session.created_at,
+            last_seen_at: session.last_seen_at,
+        }
+    }
+}
+
+/// Download the data stored about the user, as a JSON file. It includes their sign-in history, so
+/// like the deletion it is only requested from a browser session.
+async fn export_data(
+    State(state): State<AccountState>,
+    api_user: ApiUser,
+    auth_session: AuthSession,
+) -> Result<impl IntoResponse, AppError> {
+    if !api_user.is_session() {
+        return Err(AppError::Forbidden);
+    }
+
+    let backend = &auth_session.backend;
+    let user = api_user.user;
+    let takeout = Takeout {
+        exported_at: chrono::Utc::now().naive_utc(),
+        passkeys: backend.passkeys(&user).await?.into_iter().map(TakeoutPasskey::from).collect(),
+        api_tokens: backend.api_tokens(&user).await?.into_iter().map(TakeoutToken::from).collect(),
+        identities: backend.oidc_identities(&user).await?.into_iter().map(TakeoutIdentity::from).collect(),
+        sessions: state.store.user_sessions(user.id).await.map_err(adapt_app_error)?
+            .into_iter()
+            .map(TakeoutSession::from)
+            .collect(),
+        profile: Profile::from(user),
+    };
+
+    Ok(([(CONTENT_DISPOSITION, "attachment; filename=\"anthere-takeout.json\"")], Json(takeout)))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::body::Body;
+    use axum::http::{header::{CONTENT_TYPE, COOKIE, SET_COOKIE}, Request};
+    use axum_login::AuthManagerLayerBuilder;
+    use diesel::prelude::*;
+    use futures::FutureExt;
+    use tower::ServiceExt;
+    use tower_sessions::SessionManagerLayer;
+    use crate::Config;
+    use crate::auth::{AuthCredentials, Backend, Credentials};
+    use crate::db::TestDb;
+    use crate::store::PgStore;
+    use crate::webauthn::Webauthn;
+    use super::*;
+
+    fn get_db_pool() -> TestDb {
+        let config = Config::new().unwrap();
+        TestDb::new(&config)
+    }
+
+    fn app(backend: Backend, store: AppStore) -> Router<()> {
+        router(store.clone(), chrono::TimeDelta::days(30))
+            .route("/login", post(|mut auth_session: AuthSession, Json(creds): Json<Credentials>| async move {
+                let user = auth_session.authenticate(AuthCredentials::Password(creds)).await.unwrap().unwrap();
+                auth_session.login(&user).await.unwrap();
+            }))
+            .layer(AuthManagerLayerBuilder::new(backend, SessionManagerLayer::new(store)).build())
+    }
+
+    /// Log in, returning the session cookie
+    async fn login(app: &Router<()>) -> String {
+        let body = serde_json::json!({ "email": "marcus.miller@bass.com", "password": "secr3t" }).to_string();
+        let request = Request::post("/login").header(CONTENT_TYPE, "application/json").body(Body::from(body)).unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        let cookie = response.headers()[SET_COOKIE].to_str().unwrap();
+        cookie.split(';').next().unwrap().to_string()
+    }
+
+    #[tokio::test]
+    async fn test_export_and_delete() {
+        let db = get_db_pool();
+        db.run_test(|pool| async move {
+            use crate::schema::users::dsl::*;
+
+            let conn = &mut pool.get().unwrap();
+            let user: User = users.filter(email.eq("marcus.miller@bass.com")).select(User::as_select()).first(conn).unwrap();
+            let backend = Backend::new(pool.clone(), Webauthn::new("localhost", "Anthère", "http://localhost:7878"));
+            let (_, secret) = backend.create_api_token(&user, "export script", &[Scope::Read], None).await.unwrap();
+            let store = AppStore::Postgres(PgStore::new(pool.clone()));
+            let app = app(backend.clone(), store.clone());
+            let cookie = login(&app).await;
+
+            let request = Request::get("/api/account/export").header(COOKIE, &cookie).body(Body::empty()).unwrap();
+            let response = app.clone().oneshot(request).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            assert!(response.headers()[CONTENT_DISPOSITION].to_str().unwrap().starts_with("attachment"));
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+            let takeout: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            assert_eq!(takeout["profile"]["email"], "marcus.miller@bass.com");
+            assert_eq!(takeout["api_tokens"][0]["name"], "export script");
+            assert_eq!(takeout["sessions"].as_array().unwrap().len(), 1);
+            assert!(!String::from_utf8_lossy(&body).contains("hash"));
+
+            let request = Request::post("/api/account/deletion").header(COOKIE, &cookie).body(Body::empty()).unwrap();
+            assert_eq!(app.clone().oneshot(request).await.unwrap().status(), StatusCode::ACCEPTED);
+
+            // signed out everywhere, tokens included
+            let request = Request::get("/api/account/export").header(COOKIE, &cookie).body(Body::empty()).unwrap();
+            assert_eq!(app.clone().oneshot(request).await.unwrap().status(), StatusCode::UNAUTHORIZED);
+            assert!(store.user_sessions(user.id).await.unwrap().is_empty());
+            assert!(backend.authenticate_api_token(&secret).await.unwrap().is_none());
+        }.boxed()).await;
+    }
+}
@@ -1,7 +1,94 @@
+use async_trait::async_trait;
 use axum::{Router, routing::{get, post}};
+use axum::extract::{FromRequestParts, Request};
+use axum::http::{Method, request::Parts};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use axum_extra::{TypedHeader, headers::{Authorization, authorization::Bearer}};
+use crate::api_token::Scope;
+use crate::auth::AuthSession;
+use crate::errors::AppError;
+use crate::models::User;
+use crate::store::AppStore;
 
-pub fn router() -> Router<()> {
+mod account;
+mod passkeys;
+mod sessions;
+mod tokens;
+
+pub fn router(store: AppStore, deletion_grace_period: chrono::TimeDelta) -> Router<()> {
     Router::new()
         .route("/api/xxx", get(|| async { todo!() }))
         .route("/api/yyy", post(|| async { todo!() }))
+        .merge(passkeys::router())
+        .merge(tokens::router())
+        .merge(sessions::router(store.clone()))
+        .merge(account::router(store, deletion_grace_period))
+}
+
+/// The user calling the API, authenticated either by the session cookie or by a personal access token
+#[derive(Clone, Debug)]
+pub struct ApiUser {
+    pub user: User,
+    /// Scopes granted by the access token, `None` for a session which is granted everything
+    scopes: Option<Vec<Scope>>,
+}
+
+impl ApiUser {
+    pub fn is_session(&self) -> bool {
+        self.scopes.is_none()
+    }
+
+    pub fn allows(&self, scope: Scope) -> bool {
+        match &self.scopes {
+            Some(scopes) => scopes.contains(&scope),
+            None => true,
+        }
+    }
+
+    pub fn require(&self, scope: Scope) -> Result<(), AppError> {
+        if self.allows(scope) { Ok(()) } else { Err(AppError::Forbidden) }
+    }
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for ApiUser {
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        // already authenticated by `require_api_user`
+        if let Some(api_user) = parts.extensions.get::<ApiUser>() {
+            return Ok(api_user.clone());
+        }
+
+        let auth_session = AuthSession::from_request_parts(parts, state).await
+            .or(Err(AppError::InternalServerError))?;
+
+        if let Ok(TypedHeader(Authorization(bearer))) =
+            TypedHeader::<Authorization<Bearer>>::from_request_parts(parts, state).await {
+            return match auth_session.backend.authenticate_api_token(bearer.token()).await? {
+                Some((user, token)) => Ok(ApiUser { user, scopes: Some(token.scopes()) }),
+                None => Err(AppError::Unauthorized),
+            };
+        }
+
+        auth_session.user
+            .map(|user| ApiUser { user, scopes: None })
+            .ok_or(AppError::Unauthorized)
+    }
+}
+
+/// Middleware requiring a logged-in user or a valid access token. Tokens need the `read` scope
+/// for safe methods and the `write` scope for the others; routes may require more (e.g. `import`).
+pub async fn require_api_user(api_user: ApiUser, mut req: Request, next: Next) -> Response {
+    let scope = match *req.method() {
+        Method::GET | Method::HEAD | Method::OPTIONS => Scope::Read,
+        _ => Scope::Write,
+    };
+    if let Err(error) = api_user.require(scope) {
+        return error.into_response();
+    }
+
+    req.extensions_mut().insert(api_user);
+    next.run(req).await
 }
\ No newline at end of file
@@ -0,0 +1,95 @@
+use axum::{extract, Json, Router, routing::post};
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use serde::{Deserialize, Serialize};
+use tower_sessions::Session;
+use crate::auth::AuthSession;
+use crate::errors::{adapt_app_error, AppError};
+use crate::models::WebauthnCredential;
+use crate::webauthn::{Challenge, RegisterPublicKeyCredential};
+
+const PASSKEY_REGISTRATION_CHALLENGE_KEY: &str = "passkey_registration_challenge";
+/// Names are shown in lists of passkeys, so they are kept short
+const MAX_NAME_LENGTH: usize = 64;
+
+pub fn router() -> Router<()> {
+    Router::new()
+        .route("/api/passkeys/register/start", post(start_registration))
+        .route("/api/passkeys/register/finish", post(finish_registration))
+}
+
+#[derive(Deserialize)]
+struct PasskeyRegistration {
+    name: String,
+    credential: RegisterPublicKeyCredential,
+}
+
+#[derive(Serialize)]
+struct Passkey {
+    id: i32,
+    name: String,
+    created_at: chrono::NaiveDateTime,
+}
+
+impl From<WebauthnCredential> for Passkey {
+    fn from(credential: WebauthnCredential) -> Self {
+        Passkey { id: credential.id, name: credential.name, created_at: credential.created_at }
+    }
+}
+
+async fn start_registration(auth_session: AuthSession, session: Session) -> Result<impl IntoResponse, AppError> {
+    let user = auth_session.user.ok_or(AppError::Unauthorized)?;
+
+    // prevent registering the same authenticator twice
+    let existing = auth_session.backend.passkeys(&user).await?;
+    let (options, challenge) = auth_session.backend.webauthn().start_registration(&user, &existing);
+
+    session.insert(PASSKEY_REGISTRATION_CHALLENGE_KEY, challenge).await.map_err(adapt_app_error)?;
+    Ok(Json(options))
+}
+
+async fn finish_registration(
+    auth_session: AuthSession,
+    session: Session,
+    extract::Json(registration): extract::Json<PasskeyRegistration>,
+) -> Result<impl IntoResponse, AppError> {
+    let user = auth_session.user.ok_or(AppError::Unauthorized)?;
+    let name = passkey_name(&registration.name)?;
+
+    let challenge = session.remove::<Challenge>(PASSKEY_REGISTRATION_CHALLENGE_KEY).await
+        .map_err(adapt_app_error)?
+        .ok_or(AppError::Validation(String::from("no passkey registration in progress")))?;
+
+    let registered = auth_session.backend.webauthn().finish_registration(&challenge, &registration.credential)
+        .map_err(|e| {
+            tracing::warn!("Passkey registration rejected: {}", e);
+            AppError::Validation(String::from("the passkey could not be verified"))
+        })?;
+
+    let credential = auth_session.backend.add_passkey(&user, name, registered).await?;
+    Ok((StatusCode::CREATED, Json(Passkey::from(credential))))
+}
+
+fn passkey_name(name: &str) -> Result<&str, AppError> {
+    let name = name.trim();
+    if name.is_empty() {
+        return Err(AppError::Validation(String::from("name must not be blank")));
+    }
+    if name.chars().count() > MAX_NAME_LENGTH {
+        return Err(AppError::Validation(format!("name must be at most {} characters", MAX_NAME_LENGTH)));
+    }
+    Ok(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_passkey_name() {
+        assert_eq!(passkey_name("  YubiKey 5C ").unwrap(), "YubiKey 5C");
+        assert!(matches!(passkey_name(" \t"), Err(AppError::Validation(_))));
+        assert!(passkey_name(&"é".repeat(MAX_NAME_LENGTH)).is_ok());
+        assert!(matches!(passkey_name(&"é".repeat(MAX_NAME_LENGTH + 1)), Err(AppError::Validation(_))));
+    }
+}
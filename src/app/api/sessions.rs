@@ -0,0 +1,98 @@
+use axum::{extract::{Path, State}, Json, Router, routing::{delete, get}};
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use serde::Serialize;
+use tower_sessions::Session;
+use crate::app::api::ApiUser;
+use crate::errors::{adapt_app_error, AppError};
+use crate::models;
+use crate::store::AppStore;
+
+pub fn router(store: AppStore) -> Router<()> {
+    Router::new()
+        .route("/api/sessions", get(list_sessions).delete(revoke_other_sessions))
+        .route("/api/sessions/:handle", delete(revoke_session))
+        .with_state(store)
+}
+
+#[derive(Serialize)]
+struct ActiveSession {
+    handle: String,
+    ip: Option<String>,
+    user_agent: Option<String>,
+    #[serde(with = "time::serde::rfc3339")]
+    created_at: time::OffsetDateTime,
+    #[serde(with = "time::serde::rfc3339")]
+    last_seen_at: time::OffsetDateTime,
+    /// Whether this is the session making the request
+    current: bool,
+}
+
+impl ActiveSession {
+    fn new(session: models::Session, current_id: Option<&str>) -> Self {
+        ActiveSession {
+            handle: session.handle(),
+            ip: session.ip.map(|ip| ip.addr().to_string()),
+            current: current_id == Some(session.id.as_str()),
+            user_agent: session.user_agent,
+            created_at: session.created_at,
+            last_seen_at: session.last_seen_at,
+        }
+    }
+}
+
+// Like tokens, sessions are managed from a browser session only
+async fn list_sessions(
+    State(store): State<AppStore>,
+    api_user: ApiUser,
+    session: Session,
+) -> Result<impl IntoResponse, AppError> {
+    if !api_user.is_session() {
+        return Err(AppError::Forbidden);
+    }
+
+    let current_id = session.id().map(|id| id.to_string());
+    let sessions = store.user_sessions(api_user.user.id).await.map_err(adapt_app_error)?;
+    Ok(Json(sessions.into_iter()
+        .map(|s| ActiveSession::new(s, current_id.as_deref()))
+        .collect::<Vec<_>>()))
+}
+
+async fn revoke_session(
+    State(store): State<AppStore>,
+    api_user: ApiUser,
+    session: Session,
+    Path(handle): Path<String>,
+) -> Result<StatusCode, AppError> {
+    use tower_sessions::SessionStore;
+
+    if !api_user.is_session() {
+        return Err(AppError::Forbidden);
+    }
+
+    let sessions = store.user_sessions(api_user.user.id).await.map_err(adapt_app_error)?;
+    let target = sessions.into_iter().find(|s| s.handle() == handle).ok_or(AppError::NotFound)?;
+
+    // revoking the current session is a logout
+    if session.id().map(|id| id.to_string()).as_deref() == Some(target.id.as_str()) {
+        session.flush().await.map_err(adapt_app_error)?;
+        return Ok(StatusCode::NO_CONTENT);
+    }
+
+    let target_id = target.id.parse().or(Err(AppError::InternalServerError))?;
+    store.delete(&target_id).await.map_err(adapt_app_error)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn revoke_other_sessions(
+    State(store): State<AppStore>,
+    api_user: ApiUser,
+    session: Session,
+) -> Result<StatusCode, AppError> {
+    if !api_user.is_session() {
+        return Err(AppError::Forbidden);
+    }
+
+    store.delete_user_sessions(api_user.user.id, session.id().as_ref()).await.map_err(adapt_app_error)?;
+    Ok(StatusCode::NO_CONTENT)
+}
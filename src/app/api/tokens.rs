@@ -0,0 +1,123 @@
+use axum::{extract, Json, Router, routing::{delete, get}};
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use serde::{Deserialize, Serialize};
+use crate::api_token::Scope;
+use crate::app::api::ApiUser;
+use crate::auth::AuthSession;
+use crate::errors::AppError;
+use crate::models::ApiToken;
+
+/// Tokens expire after ten years at most
+const MAX_EXPIRY_DAYS: u32 = 3650;
+
+pub fn router() -> Router<()> {
+    Router::new()
+        .route("/api/tokens", get(list_tokens).post(create_token))
+        .route("/api/tokens/:id", delete(revoke_token))
+}
+
+#[derive(Deserialize)]
+struct TokenRequest {
+    name: String,
+    scopes: Vec<Scope>,
+    expires_in_days: Option<u32>,
+}
+
+#[derive(Serialize)]
+struct Token {
+    id: i32,
+    name: String,
+    scopes: Vec<Scope>,
+    expires_at: Option<chrono::NaiveDateTime>,
+    last_used_at: Option<chrono::NaiveDateTime>,
+    created_at: chrono::NaiveDateTime,
+    /// Only sent once, on creation
+    #[serde(skip_serializing_if = "Option::is_none")]
+    token: Option<String>,
+}
+
+impl From<ApiToken> for Token {
+    fn from(token: ApiToken) -> Self {
+        Token {
+            id: token.id,
+            scopes: token.scopes(),
+            name: token.name,
+            expires_at: token.expires_at,
+            last_used_at: token.last_used_at,
+            created_at: token.created_at,
+            token: None,
+        }
+    }
+}
+
+// Tokens are managed from a browser session only, so a leaked token cannot mint new ones
+async fn list_tokens(api_user: ApiUser, auth_session: AuthSession) -> Result<impl IntoResponse, AppError> {
+    if !api_user.is_session() {
+        return Err(AppError::Forbidden);
+    }
+
+    let tokens = auth_session.backend.api_tokens(&api_user.user).await?;
+    Ok(Json(tokens.into_iter().map(Token::from).collect::<Vec<_>>()))
+}
+
+async fn create_token(
+    api_user: ApiUser,
+    auth_session: AuthSession,
+    extract::Json(request): extract::Json<TokenRequest>,
+) -> Result<impl IntoResponse, AppError> {
+    if !api_user.is_session() {
+        return Err(AppError::Forbidden);
+    }
+    if request.name.trim().is_empty() {
+        return Err(AppError::Validation(String::from("name must not be blank")));
+    }
+    if request.scopes.is_empty() {
+        return Err(AppError::Validation(String::from("at least one scope is required")));
+    }
+
+    let expiry = request.expires_in_days.map(token_expiry).transpose()?;
+
+    let (token, secret) = auth_session.backend.create_api_token(&api_user.user, request.name.trim(), &request.scopes, expiry).await?;
+    let token = Token { token: Some(secret), ..Token::from(token) };
+    Ok((StatusCode::CREATED, Json(token)))
+}
+
+fn token_expiry(days: u32) -> Result<chrono::NaiveDateTime, AppError> {
+    let invalid = || AppError::Validation(format!("expires_in_days must be at most {}", MAX_EXPIRY_DAYS));
+    if days > MAX_EXPIRY_DAYS {
+        return Err(invalid());
+    }
+    chrono::Utc::now().naive_utc()
+        .checked_add_signed(chrono::TimeDelta::days(days.into()))
+        .ok_or_else(invalid)
+}
+
+async fn revoke_token(
+    api_user: ApiUser,
+    auth_session: AuthSession,
+    extract::Path(token_id): extract::Path<i32>,
+) -> Result<StatusCode, AppError> {
+    if !api_user.is_session() {
+        return Err(AppError::Forbidden);
+    }
+
+    match auth_session.backend.revoke_api_token(&api_user.user, token_id).await? {
+        true => Ok(StatusCode::NO_CONTENT),
+        false => Err(AppError::NotFound),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_token_expiry() {
+        let expiry = token_expiry(30).unwrap();
+        assert!((29..=30).contains(&(expiry - chrono::Utc::now().naive_utc()).num_days()));
+        assert!(token_expiry(MAX_EXPIRY_DAYS).is_ok());
+        assert!(matches!(token_expiry(MAX_EXPIRY_DAYS + 1), Err(AppError::Validation(_))));
+        assert!(matches!(token_expiry(4_000_000_000), Err(AppError::Validation(_))));
+    }
+}
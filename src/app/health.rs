@@ -0,0 +1,186 @@
+//! This module contains the health endpoints, reporting the state of the service and its resources:
+//! `/healthz` whether the process is up, and `/readyz` whether it can serve requests
+
+use axum::{extract::{FromRef, State}, http::StatusCode, Json, Router, routing::get};
+use diesel::PgConnection;
+use diesel::r2d2::{ConnectionManager, Pool};
+use diesel_migrations::MigrationHarness;
+use serde::Serialize;
+use tokio::task::AbortHandle;
+use crate::db::run_blocking;
+use crate::errors::adapt_app_error;
+use crate::store::AppStore;
+use crate::db::migrate::MIGRATIONS;
+
+#[derive(Clone)]
+struct HealthState {
+    db: Pool<ConnectionManager<PgConnection>>,
+    store: AppStore,
+    /// The task deleting expired sessions, for the stores which need one
+    deletion_task: Option<AbortHandle>,
+}
+
+impl FromRef<HealthState> for Pool<ConnectionManager<PgConnection>> {
+    fn from_ref(state: &HealthState) -> Self {
+        state.db.clone()
+    }
+}
+
+pub fn router(db: Pool<ConnectionManager<PgConnection>>, store: AppStore, deletion_task: Option<AbortHandle>) -> Router<()> {
+    Router::new()
+        .route("/health", get(health))
+        .route("/healthz", get(|| async { "ok" }))
+        .route("/readyz", get(readyz))
+        .with_state(HealthState { db, store, deletion_task })
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct PoolStats {
+    max_size: u32,
+    connections: u32,
+    idle_connections: u32,
+}
+
+impl PoolStats {
+    pub fn of(db: &Pool<ConnectionManager<PgConnection>>) -> Self {
+        let state = db.state();
+        PoolStats {
+            max_size: db.max_size(),
+            connections: state.connections,
+            idle_connections: state.idle_connections,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Health {
+    status: &'static str,
+    database_pool: PoolStats,
+}
+
+async fn health(State(db): State<Pool<ConnectionManager<PgConnection>>>) -> Json<Health> {
+    Json(Health { status: "ok", database_pool: PoolStats::of(&db) })
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+struct Readiness {
+    database: bool,
+    migrations: bool,
+    session_deletion: bool,
+    session_store: bool,
+}
+
+impl Readiness {
+    fn is_ready(&self) -> bool {
+        self.database && self.migrations && self.session_deletion && self.session_store
+    }
+}
+
+async fn readyz(State(state): State<HealthState>) -> (StatusCode, Json<Readiness>) {
+    use diesel::{sql_query, RunQueryDsl};
+
+    let database = run_blocking(&state.db, |conn| {
+        sql_query("SELECT 1").execute(conn).map_err(adapt_app_error)
+    }).await;
+    let pending_migrations = run_blocking(&state.db, |conn| {
+        conn.has_pending_migration(MIGRATIONS).map_err(adapt_app_error)
+    }).await;
+    let readiness = Readiness {
+        database: database.is_ok(),
+        migrations: pending_migrations == Ok(false),
+        session_deletion: state.deletion_task.as_ref().is_none_or(|task| !task.is_finished()),
+        session_store: store_reachable(&state.store).await,
+    };
+
+    let status = if readiness.is_ready() { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+    if status != StatusCode::OK {
+        tracing::warn!(?readiness, "not ready");
+    }
+    (status, Json(readiness))
+}
+
+async fn store_reachable(store: &AppStore) -> bool {
+    match store.ping().await {
+        Ok(()) => true,
+        Err(e) => {
+            tracing::warn!("session store unreachable: {}", e);
+            false
+        }
+    }
+}
+
+/// Periodically log the pool usage, warning when every connection is in use
+pub async fn log_pool_stats(db: Pool<ConnectionManager<PgConnection>>, period: std::time::Duration) {
+    let mut interval = tokio::time::interval(period);
+    loop {
+        interval.tick().await;
+        let stats = PoolStats::of(&db);
+        if stats.idle_connections == 0 && stats.connections == stats.max_size {
+            tracing::warn!(?stats, "database pool exhausted");
+        } else {
+            tracing::debug!(?stats, "database pool");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::body::Body;
+    use axum::http::Request;
+    use futures::FutureExt;
+    use tower::ServiceExt;
+    use crate::Config;
+    use crate::db::TestDb;
+    use crate::store::PgStore;
+    use super::*;
+
+    fn get_db_pool() -> TestDb {
+        let config = Config::new().unwrap();
+        TestDb::new(&config)
+    }
+
+    async fn get(app: Router<()>, uri: &str) -> (StatusCode, serde_json::Value) {
+        let response = app.oneshot(Request::get(uri).body(Body::empty()).unwrap()).await.unwrap();
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (status, serde_json::from_slice(&body).unwrap_or(serde_json::Value::Null))
+    }
+
+    #[tokio::test]
+    async fn test_ready() {
+        let db = get_db_pool();
+        db.run_test(|pool| async move {
+            let app = router(pool.clone(), AppStore::Postgres(PgStore::new(pool)), None);
+
+            let (status, _) = get(app.clone(), "/healthz").await;
+            assert_eq!(status, StatusCode::OK);
+
+            let (status, body) = get(app, "/readyz").await;
+            assert_eq!(status, StatusCode::OK);
+            assert_eq!(body, serde_json::json!({
+                "database": true,
+                "migrations": true,
+                "session_deletion": true,
+                "session_store": true,
+            }));
+        }.boxed()).await;
+    }
+
+    #[tokio::test]
+    async fn test_database_down() {
+        // nothing listens there, and connections are not attempted until checked out
+        let pool = Pool::builder()
+            .connection_timeout(std::time::Duration::from_millis(200))
+            .build_unchecked(ConnectionManager::new("postgres://anthere@127.0.0.1:1/anthere"));
+        let app = router(pool.clone(), AppStore::Postgres(PgStore::new(pool)), None);
+
+        // the process is still alive
+        let (status, _) = get(app.clone(), "/healthz").await;
+        assert_eq!(status, StatusCode::OK);
+
+        let (status, body) = get(app, "/readyz").await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(body["database"], false);
+        assert_eq!(body["session_store"], false);
+    }
+}
@@ -1,19 +1,38 @@
+use std::net::SocketAddr;
 use axum_csrf::CsrfLayer;
-use axum_login::{AuthManagerLayerBuilder, login_required};
+use axum::extract::{ConnectInfo, Request, State};
+use axum::http::header::USER_AGENT;
+use axum::middleware::{from_fn, from_fn_with_state, Next};
+use axum::response::Response;
+use axum_login::{AuthManagerLayerBuilder, AuthSession, permission_required};
 use diesel::PgConnection;
-use diesel_migrations::{embed_migrations, EmbeddedMigrations, MigrationHarness};
 use diesel::r2d2::{ConnectionManager, Pool};
 use tokio::{signal, task::AbortHandle};
-use tower_sessions::{ExpiredDeletion, Expiry, SessionManagerLayer, cookie::Key};
-use crate::{Config, get_connection_pool};
-use crate::auth::Backend;
+use tower_http::{LatencyUnit, trace::{DefaultOnResponse, TraceLayer}};
+use tracing::Level;
+use tower_sessions::{ExpiredDeletion, Expiry, Session, SessionManagerLayer};
+use crate::{Config, build_connection_pool};
+use crate::account_deletion::continuously_erase_accounts;
+use crate::auth::{Backend, Permission};
+use crate::db::migrate;
 use crate::errors::adapt_app_error;
-use crate::store::PgStore;
-
-pub const MIGRATIONS: EmbeddedMigrations = embed_migrations!("src/db/migrations/");
+use crate::oidc::Oidc;
+use crate::session_key::accept_previous_keys;
+use crate::caching_store::CachingStore;
+use crate::csrf::{CsrfExemptions, verify_csrf};
+use crate::config::SessionStoreConfig;
+use crate::redis_store::RedisStore;
+use crate::prometheus::{self, track_http};
+use crate::request_id::request_id;
+use crate::telemetry::{request_span, spawn_job};
+use crate::remember_me::{remember_user, RememberMe};
+use crate::store::{AUTH_DATA_KEY, AppStore, PgStore, SESSION_META_KEY, SessionMeta};
+use crate::webauthn::Webauthn;
 
 mod public;
 mod api;
+mod admin;
+mod health;
 
 pub struct App {
     db: Pool<ConnectionManager<PgConnection>>,
@@ -21,59 +40,185 @@ pub struct App {
 }
 
 impl App {
-    pub async fn new() -> Result<Self, Box<dyn std::error::Error>> {
-
-        let config = Config::new().map_err(adapt_app_error)?;
-        let db = get_connection_pool(&config.database_url);
+    pub async fn new(config: Config) -> Result<Self, Box<dyn std::error::Error>> {
+        let db = build_connection_pool(&config.database_url, &config.pool);
         
         let mut conn = db.get().map_err(adapt_app_error)?;
-        conn.run_pending_migrations(MIGRATIONS).map_err(adapt_app_error)?;
+        if config.auto_migrate {
+            for version in migrate::run_pending(&mut conn).map_err(adapt_app_error)? {
+                tracing::info!("applied migration {}", version);
+            }
+        }
+
+        // serving with an outdated schema would fail in unexpected ways
+        let pending = migrate::pending(&mut conn).map_err(adapt_app_error)?;
+        if !pending.is_empty() {
+            return Err(format!(
+                "{} pending migration(s), from {}: apply them with `anthere migrate`",
+                pending.len(),
+                pending[0],
+            ).into());
+        }
 
         Ok(App { db, config })
     }
     
     pub async fn serve(&self) -> Result<(), Box<dyn std::error::Error>> {
+        spawn_job("pool_stats", health::log_pool_stats(self.db.clone(), std::time::Duration::from_secs(60)));
+
+        // the metrics are served on the admin port
+        if let Some(metrics_addr) = self.config.metrics_addr {
+            let handle = prometheus::install()?;
+            spawn_job("metrics_upkeep", prometheus::run_upkeep(handle.clone(), std::time::Duration::from_secs(10)));
+            let listener = tokio::net::TcpListener::bind(metrics_addr).await?;
+            tracing::debug!("serving metrics on {}", listener.local_addr()?);
+            let metrics_app = prometheus::router(handle, self.db.clone());
+            spawn_job("metrics_server", async move {
+                if let Err(e) = axum::serve(listener, metrics_app).await {
+                    tracing::error!("metrics server failed: {}", e);
+                }
+            });
+        }
+
         // handle the session
-        let session_store = PgStore::new(self.db.clone());
-        let deletion_task = tokio::task::spawn(
-            session_store
-                .clone()
-                .continuously_delete_expired(tokio::time::Duration::from_secs(60)),
-        );
+        let cipher = self.config.session_cipher.clone();
+        let (session_store, deletion_task) = match &self.config.session_store {
+            SessionStoreConfig::Postgres { cache_size } => {
+                let store = PgStore::new(self.db.clone()).with_cipher(cipher);
+                let deletion_task = spawn_job(
+                    "session_deletion",
+                    store
+                        .clone()
+                        .continuously_delete_expired(tokio::time::Duration::from_secs(60)),
+                );
+                let store = match cache_size {
+                    Some(capacity) => AppStore::CachedPostgres(CachingStore::new(store, *capacity)),
+                    None => AppStore::Postgres(store),
+                };
+                (store, Some(deletion_task))
+            }
+            // sessions expire natively in Redis
+            SessionStoreConfig::Redis { url } => {
+                let store = RedisStore::connect(url).await.map_err(adapt_app_error)?.with_cipher(cipher);
+                (AppStore::Redis(store), None)
+            }
+        };
 
-        // Generate a cryptographic key to sign the session cookie.
-        let key = Key::generate();
+        spawn_job(
+            "account_erasure",
+            continuously_erase_accounts(self.db.clone(), session_store.clone(), std::time::Duration::from_secs(60)),
+        );
 
-        let session_layer = SessionManagerLayer::new(session_store)
-            .with_secure(false)
-            .with_expiry(Expiry::OnInactivity(time::Duration::days(1)))
-            .with_signed(key);
+        let cookie = &self.config.cookie;
+        let mut session_layer = SessionManagerLayer::new(session_store.clone())
+            .with_name(cookie.name.clone())
+            .with_path(cookie.path.clone())
+            .with_same_site(cookie.same_site)
+            .with_secure(cookie.secure)
+            .with_expiry(Expiry::OnInactivity(cookie.inactivity_expiry));
+        if let Some(domain) = &cookie.domain {
+            session_layer = session_layer.with_domain(domain.clone());
+        }
         
         // handle the authentication
-        let backend = Backend::new(self.db.clone());
-        let auth_layer = AuthManagerLayerBuilder::new(backend, session_layer).build();
-
-        let app = api::router()
-            .route_layer(login_required!(Backend))
-            .merge(public::router())
-            .layer(auth_layer)
-            .layer(CsrfLayer::new(self.config.csrf_config.clone()));
+        let webauthn = Webauthn::new(&self.config.webauthn_rp_id, "Anthère", &self.config.webauthn_rp_origin);
+        let mut backend = Backend::new(self.db.clone(), webauthn);
+        if let Some(oidc_config) = &self.config.oidc {
+            let oidc = Oidc::discover(oidc_config).await.map_err(adapt_app_error)?;
+            backend = backend.with_oidc(oidc);
+        }
+        let remember = RememberMe::new(session_store.clone(), self.config.cookie.clone());
+        let app = api::router(session_store.clone(), self.config.account_deletion_grace_period)
+            .route_layer(from_fn(api::require_api_user))
+            .merge(admin::router(self.db.clone(), session_store.clone())
+                .route_layer(permission_required!(Backend, Permission::ManageUsers)))
+            .merge(public::router(remember.clone()))
+            .merge(health::router(self.db.clone(), session_store.clone(), deletion_task.as_ref().map(|task| task.abort_handle())))
+            .layer(from_fn_with_state(self.config.cookie.absolute_expiry, track_session))
+            .layer(from_fn_with_state(remember, remember_user));
+
+        // the session cookie is either encrypted or signed
+        let session_key = self.config.session_keys.current().clone();
+        let app = if self.config.session_keys.is_private() {
+            app.layer(AuthManagerLayerBuilder::new(backend, session_layer.with_private(session_key)).build())
+        } else {
+            app.layer(AuthManagerLayerBuilder::new(backend, session_layer.with_signed(session_key)).build())
+        };
+
+        let app = app
+            .layer(from_fn_with_state(self.config.session_keys.clone(), accept_previous_keys))
+            .layer(from_fn_with_state(CsrfExemptions::new(self.config.csrf_exempt_paths.clone()), verify_csrf))
+            .layer(CsrfLayer::new(self.config.csrf_config.clone()))
+            .layer(from_fn(track_http))
+            .layer(TraceLayer::new_for_http()
+                .make_span_with(request_span)
+                .on_response(DefaultOnResponse::new().level(Level::INFO).latency_unit(LatencyUnit::Millis)))
+            .layer(from_fn(request_id));
         
-        let addr = format!("{}:{}", self.config.host, self.config.port);
+        let addr = SocketAddr::new(self.config.host, self.config.port);
         let listener = tokio::net::TcpListener::bind(addr).await?;
         
         tracing::debug!("listening on {}", listener.local_addr()?);
-        axum::serve(listener, app.into_make_service())
-            .with_graceful_shutdown(shutdown_signal(deletion_task.abort_handle()))
+        axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>())
+            .with_graceful_shutdown(shutdown_signal(deletion_task.as_ref().map(|task| task.abort_handle())))
             .await?;
         
-        deletion_task.await??;
+        if let Some(deletion_task) = deletion_task {
+            deletion_task.await??;
+        }
         
         Ok(())
     }
 }
 
-async fn shutdown_signal(deletion_task_abort_handle: AbortHandle) {
+/// Record the client address and user agent of logged-in sessions, so users can review them.
+/// Sessions are only saved when modified, so the metadata is also refreshed once a minute.
+/// Sessions older than the absolute expiry are logged out before handling the request.
+async fn track_session(
+    State(absolute_expiry): State<Option<time::Duration>>,
+    session: Session,
+    mut req: Request,
+    next: Next,
+) -> Response {
+    let ip = req.extensions().get::<ConnectInfo<SocketAddr>>().map(|ConnectInfo(addr)| addr.ip());
+    let user_agent = req.headers().get(USER_AGENT)
+        .and_then(|user_agent| user_agent.to_str().ok())
+        .map(str::to_string);
+
+    if let Some(user) = req.extensions().get::<AuthSession<Backend>>().and_then(|auth_session| auth_session.user.as_ref()) {
+        tracing::Span::current().record("user_id", user.id);
+    }
+
+    let now = time::OffsetDateTime::now_utc().unix_timestamp();
+    let previous = session.get::<SessionMeta>(SESSION_META_KEY).await.ok().flatten().unwrap_or_default();
+    let expired = absolute_expiry.is_some_and(|expiry| {
+        previous.started_at > 0 && now - previous.started_at >= expiry.whole_seconds()
+    });
+    if expired {
+        if let Some(auth_session) = req.extensions_mut().get_mut::<AuthSession<Backend>>() {
+            if let Err(e) = auth_session.logout().await {
+                tracing::warn!("failed to log out an expired session: {}", e);
+            }
+        }
+    }
+
+    let response = next.run(req).await;
+
+    if let Ok(Some(_)) = session.get::<serde_json::Value>(AUTH_DATA_KEY).await {
+        let previous = session.get::<SessionMeta>(SESSION_META_KEY).await.ok().flatten().unwrap_or_default();
+        if previous.ip != ip || previous.user_agent != user_agent || now - previous.touched_at >= 60 {
+            let started_at = if previous.started_at > 0 { previous.started_at } else { now };
+            let meta = SessionMeta { ip, user_agent, touched_at: now, started_at };
+            if let Err(e) = session.insert(SESSION_META_KEY, meta).await {
+                tracing::warn!("failed to update the session metadata: {}", e);
+            }
+        }
+    }
+
+    response
+}
+
+async fn shutdown_signal(deletion_task_abort_handle: Option<AbortHandle>) {
     let ctrl_c = async {
         signal::ctrl_c()
             .await
@@ -92,7 +237,10 @@ async fn shutdown_signal(deletion_task_abort_handle: AbortHandle) {
         let terminate = std::future::pending::<()>();
 
     tokio::select! {
-        _ = ctrl_c => { deletion_task_abort_handle.abort() },
-        _ = terminate => { deletion_task_abort_handle.abort() },
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+    if let Some(abort_handle) = deletion_task_abort_handle {
+        abort_handle.abort();
     }
 }
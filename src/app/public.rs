@@ -1,41 +1,132 @@
 /// This module contains public routes (i.e. routes that can be accessed without prior auth)
 
-use axum::{extract, Router, routing::{get, post}};
-use axum::http::StatusCode;
-use axum::response::IntoResponse;
+use axum::{extract::{self, State}, Json, Router, routing::{get, post}};
+use axum::http::{HeaderMap, StatusCode, header::SET_COOKIE};
+use axum::response::{IntoResponse, Redirect, Response};
 use axum_csrf::CsrfToken;
+use serde::Deserialize;
 use tower_sessions::Session;
-use crate::auth::{AuthSession, Credentials};
+use crate::csrf::csrf_token;
+use crate::errors::{adapt_app_error, AppError};
+use crate::auth::{AuthCredentials, AuthSession, Credentials, OidcCredentials, PasskeyCredentials};
+use crate::models::User;
+use crate::oidc::OidcFlow;
+use crate::remember_me::{request_cookie, RememberMe};
+use crate::webauthn::{Challenge, PublicKeyCredential};
 
-pub fn router() -> Router<()> {
+const PASSKEY_LOGIN_CHALLENGE_KEY: &str = "passkey_login_challenge";
+const OIDC_FLOW_KEY: &str = "oidc_flow";
+
+pub fn router(remember: RememberMe) -> Router<()> {
     Router::new()
         .route("/", get(home))
+        .route("/csrf", get(csrf_token))
         .route("/login", post(login))
+        .route("/logout", post(logout))
+        .route("/login/passkey/start", post(start_passkey_login))
+        .route("/login/passkey/finish", post(finish_passkey_login))
+        .route("/login/oidc", get(start_oidc_login))
+        .route("/login/oidc/callback", get(finish_oidc_login))
+        .with_state(remember)
 }
 
-async fn home(token: CsrfToken, session: Session) -> impl IntoResponse {
-    let authenticity_token = token.authenticity_token().unwrap();
-    match session.insert("authenticity_token", authenticity_token).await {
-        Ok(_) => token.into_response(),
-        Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response()
-    };
+// sets the CSRF cookie, whose token is served at `/csrf`
+async fn home(token: CsrfToken) -> impl IntoResponse {
+    token
 }
 
 async fn login(
-    mut auth_session: AuthSession,
+    State(remember): State<RememberMe>,
+    auth_session: AuthSession,
     extract::Json(creds): extract::Json<Credentials>,
-) -> impl IntoResponse {
-    let user = match auth_session.authenticate(creds.clone()).await {
-        Ok(Some(user)) => user,
-        Ok(None) => {
-            return StatusCode::FORBIDDEN.into_response();
-        }
-        Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+) -> Result<Response, AppError> {
+    let backend = auth_session.backend.clone();
+    let remember_me = creds.remember_me;
+    let user = authenticate_and_login(auth_session, AuthCredentials::Password(creds)).await?;
+
+    // the persistent login outlives the session, which keeps its inactivity expiry
+    let Some(expiry) = remember.expiry().filter(|_| remember_me) else {
+        return Ok(StatusCode::OK.into_response());
     };
+    let value = backend.remember_user(&user, expiry).await?;
+    let cookie = remember.set_cookie(value).ok_or(AppError::InternalServerError)?;
+    Ok((StatusCode::OK, [(SET_COOKIE, cookie)]).into_response())
+}
 
-    if auth_session.login(&user).await.is_err() {
-        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+async fn logout(
+    State(remember): State<RememberMe>,
+    mut auth_session: AuthSession,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    if let Some(value) = request_cookie(&headers) {
+        auth_session.backend.forget_remember_token(&value).await?;
     }
+    auth_session.logout().await.map_err(adapt_app_error)?;
 
-    StatusCode::OK.into_response()
-}
\ No newline at end of file
+    Ok(match remember.removal_cookie() {
+        Some(cookie) => (StatusCode::OK, [(SET_COOKIE, cookie)]).into_response(),
+        None => StatusCode::OK.into_response(),
+    })
+}
+
+async fn start_passkey_login(auth_session: AuthSession, session: Session) -> Result<impl IntoResponse, AppError> {
+    let (options, challenge) = auth_session.backend.webauthn().start_authentication();
+
+    session.insert(PASSKEY_LOGIN_CHALLENGE_KEY, challenge).await.map_err(adapt_app_error)?;
+    Ok(Json(options))
+}
+
+async fn finish_passkey_login(
+    auth_session: AuthSession,
+    session: Session,
+    extract::Json(credential): extract::Json<PublicKeyCredential>,
+) -> Result<StatusCode, AppError> {
+    // the challenge is removed so that it can only be used once
+    let challenge = session.remove::<Challenge>(PASSKEY_LOGIN_CHALLENGE_KEY).await
+        .map_err(adapt_app_error)?
+        .ok_or(AppError::Validation(String::from("no passkey login in progress")))?;
+
+    authenticate_and_login(auth_session, AuthCredentials::Passkey(PasskeyCredentials { challenge, credential })).await?;
+    Ok(StatusCode::OK)
+}
+
+async fn start_oidc_login(auth_session: AuthSession, session: Session) -> Result<Redirect, AppError> {
+    let oidc = auth_session.backend.oidc().ok_or(AppError::NotFound)?;
+    let (url, flow) = oidc.authorize();
+
+    session.insert(OIDC_FLOW_KEY, flow).await.map_err(adapt_app_error)?;
+    Ok(Redirect::to(url.as_str()))
+}
+
+#[derive(Deserialize)]
+struct OidcCallback {
+    code: String,
+    state: String,
+}
+
+async fn finish_oidc_login(
+    auth_session: AuthSession,
+    session: Session,
+    extract::Query(callback): extract::Query<OidcCallback>,
+) -> Result<Redirect, AppError> {
+    // the state, nonce and PKCE verifier are removed so that they can only be used once
+    let flow = session.remove::<OidcFlow>(OIDC_FLOW_KEY).await
+        .map_err(adapt_app_error)?
+        .ok_or(AppError::Validation(String::from("no single sign-on in progress")))?;
+
+    let creds = OidcCredentials { flow, code: callback.code, state: callback.state };
+    authenticate_and_login(auth_session, AuthCredentials::Oidc(creds)).await?;
+    Ok(Redirect::to("/"))
+}
+
+async fn authenticate_and_login(
+    mut auth_session: AuthSession,
+    creds: AuthCredentials,
+) -> Result<User, AppError> {
+    let user = auth_session.authenticate(creds).await
+        .map_err(adapt_app_error)?
+        .ok_or(AppError::Forbidden)?;
+
+    auth_session.login(&user).await.map_err(adapt_app_error)?;
+    Ok(user)
+}
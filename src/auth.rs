@@ -1,19 +1,497 @@
-use axum_login::{AuthnBackend, UserId};
+use std::collections::HashSet;
+use axum_login::{AuthnBackend, AuthzBackend, UserId};
 use async_trait::async_trait;
+use rand::RngCore;
 use diesel::PgConnection;
 use diesel::r2d2::{ConnectionManager, Pool};
 use serde::Deserialize;
 use crate::errors::{adapt_app_error, AppError};
-use crate::models::User;
+use crate::api_token::{generate_token, hash_token, Scope};
+use crate::db::run_blocking;
+use crate::models::{AccountDeletion, ApiToken, NewAccountDeletion, NewApiToken, NewOidcIdentity, NewRememberToken, NewWebauthnCredential, OidcIdentity, RememberToken, Role, User, WebauthnCredential};
+use crate::oidc::{Oidc, OidcFlow, VerifiedIdentity};
+use crate::prometheus::record_login;
+use crate::remember_me;
+use crate::telemetry::spawn_blocking;
+use crate::webauthn::{Challenge, PublicKeyCredential, RegisteredCredential, Webauthn};
 
 #[derive(Clone)]
 pub struct Backend {
     db: Pool<ConnectionManager<PgConnection>>,
+    webauthn: Webauthn,
+    oidc: Option<Oidc>,
 }
 
 impl Backend {
-    pub fn new(db: Pool<ConnectionManager<PgConnection>>) -> Self {
-        Self { db }
+    pub fn new(db: Pool<ConnectionManager<PgConnection>>, webauthn: Webauthn) -> Self {
+        Self { db, webauthn, oidc: None }
+    }
+
+    /// Enable single sign-on with an OpenID Connect provider
+    pub fn with_oidc(mut self, oidc: Oidc) -> Self {
+        self.oidc = Some(oidc);
+        self
+    }
+
+    pub fn webauthn(&self) -> &Webauthn {
+        &self.webauthn
+    }
+
+    pub fn oidc(&self) -> Option<&Oidc> {
+        self.oidc.as_ref()
+    }
+
+    pub async fn passkeys(&self, user: &User) -> Result<Vec<WebauthnCredential>, AppError> {
+        use crate::schema::webauthn_credentials::dsl::*;
+        use diesel::prelude::*;
+
+        let owner_id = user.id;
+        run_blocking(&self.db, move |conn| {
+            webauthn_credentials
+                .filter(user_id.eq(owner_id))
+                .select(WebauthnCredential::as_select())
+                .order(created_at.asc())
+                .load(conn)
+                .map_err(adapt_app_error)
+        }).await
+    }
+
+    /// The identity provider accounts linked to a user
+    pub async fn oidc_identities(&self, user: &User) -> Result<Vec<OidcIdentity>, AppError> {
+        use crate::schema::oidc_identities::dsl::*;
+        use diesel::prelude::*;
+
+        let owner_id = user.id;
+        run_blocking(&self.db, move |conn| {
+            oidc_identities
+                .filter(user_id.eq(owner_id))
+                .select(OidcIdentity::as_select())
+                .order(created_at.asc())
+                .load(conn)
+                .map_err(adapt_app_error)
+        }).await
+    }
+
+    pub async fn add_passkey(
+        &self,
+        user: &User,
+        passkey_name: &str,
+        credential: RegisteredCredential,
+    ) -> Result<WebauthnCredential, AppError> {
+        use crate::schema::webauthn_credentials::dsl::*;
+        use diesel::prelude::*;
+
+        let owner_id = user.id;
+        let passkey_name = passkey_name.to_string();
+        run_blocking(&self.db, move |conn| {
+            let new_credential = NewWebauthnCredential {
+                user_id: owner_id,
+                credential_id: credential.credential_id,
+                public_key: credential.public_key,
+                sign_count: credential.sign_count as i64,
+                name: &passkey_name,
+            };
+
+            diesel::insert_into(webauthn_credentials)
+                .values(&new_credential)
+                .returning(WebauthnCredential::as_returning())
+                .get_result(conn)
+                .map_err(adapt_app_error)
+        }).await
+    }
+
+    /// Create a personal access token. Its secret is returned only once, as we only store its hash.
+    pub async fn create_api_token(
+        &self,
+        user: &User,
+        token_name: &str,
+        token_scopes: &[Scope],
+        expiry: Option<chrono::NaiveDateTime>,
+    ) -> Result<(ApiToken, String), AppError> {
+        use crate::schema::api_tokens::dsl::*;
+        use diesel::prelude::*;
+
+        let secret = generate_token();
+        let token_hash_value = hash_token(&secret);
+        let token_scopes = token_scopes.iter().map(|scope| Some(scope.to_string())).collect();
+        let (owner_id, token_name) = (user.id, token_name.to_string());
+
+        let token = run_blocking(&self.db, move |conn| {
+            let new_token = NewApiToken {
+                user_id: owner_id,
+                name: &token_name,
+                token_hash: token_hash_value,
+                scopes: token_scopes,
+                expires_at: expiry,
+            };
+
+            diesel::insert_into(api_tokens)
+                .values(&new_token)
+                .returning(ApiToken::as_returning())
+                .get_result(conn)
+                .map_err(adapt_app_error)
+        }).await?;
+
+        Ok((token, secret))
+    }
+
+    pub async fn api_tokens(&self, user: &User) -> Result<Vec<ApiToken>, AppError> {
+        use crate::schema::api_tokens::dsl::*;
+        use diesel::prelude::*;
+
+        let owner_id = user.id;
+        run_blocking(&self.db, move |conn| {
+            api_tokens
+                .filter(user_id.eq(owner_id))
+                .select(ApiToken::as_select())
+                .order(created_at.asc())
+                .load(conn)
+                .map_err(adapt_app_error)
+        }).await
+    }
+
+    /// Returns whether a token was revoked
+    pub async fn revoke_api_token(&self, user: &User, token_id: i32) -> Result<bool, AppError> {
+        use crate::schema::api_tokens::dsl::*;
+        use diesel::prelude::*;
+
+        let owner_id = user.id;
+        let deleted = run_blocking(&self.db, move |conn| {
+            diesel::delete(api_tokens.filter(id.eq(token_id)).filter(user_id.eq(owner_id)))
+                .execute(conn)
+                .map_err(adapt_app_error)
+        }).await?;
+
+        Ok(deleted > 0)
+    }
+
+    /// Find the user owning a valid (i.e. not expired) token, and record its usage
+    pub async fn authenticate_api_token(&self, secret: &str) -> Result<Option<(User, ApiToken)>, AppError> {
+        use crate::schema::{api_tokens, users};
+        use diesel::{dsl::now, prelude::*};
+
+        let secret_hash = hash_token(secret);
+        run_blocking(&self.db, move |conn| {
+            let found = api_tokens::table
+                .inner_join(users::table)
+                .filter(api_tokens::token_hash.eq(secret_hash))
+                .filter(users::locked_at.is_null())
+                .filter(api_tokens::expires_at.is_null().or(api_tokens::expires_at.gt(chrono::Utc::now().naive_utc())))
+                .select((User::as_select(), ApiToken::as_select()))
+                .first::<(User, ApiToken)>(conn)
+                .optional()
+                .map_err(adapt_app_error)?;
+
+            if let Some((_, token)) = &found {
+                diesel::update(api_tokens::table.find(token.id))
+                    .set(api_tokens::last_used_at.eq(now))
+                    .execute(conn)
+                    .map_err(adapt_app_error)?;
+            }
+
+            Ok(found)
+        }).await
+    }
+
+    /// Start a persistent login, returning the value of its cookie
+    pub async fn remember_user(&self, user: &User, expiry: chrono::TimeDelta) -> Result<String, AppError> {
+        use crate::schema::remember_tokens::dsl::*;
+        use diesel::prelude::*;
+
+        let (new_series, token) = remember_me::generate_series();
+        let (owner_id, value) = (user.id, remember_me::cookie_value(&new_series, &token));
+        run_blocking(&self.db, move |conn| {
+            diesel::insert_into(remember_tokens)
+                .values(&NewRememberToken {
+                    user_id: owner_id,
+                    series: &new_series,
+                    token_hash: hash_token(&token),
+                    expires_at: chrono::Utc::now().naive_utc() + expiry,
+                })
+                .execute(conn)
+                .map_err(adapt_app_error)
+        }).await?;
+
+        Ok(value)
+    }
+
+    /// Check a remember-me cookie, replacing its token and extending its expiry when valid
+    pub async fn authenticate_remember_token(&self, value: &str, expiry: chrono::TimeDelta) -> Result<RememberOutcome, AppError> {
+        use crate::schema::{remember_tokens, users};
+        use diesel::prelude::*;
+
+        let Some((token_series, token)) = remember_me::parse_cookie_value(value) else {
+            return Ok(RememberOutcome::Invalid);
+        };
+        let (token_series, presented_hash) = (token_series.to_string(), hash_token(token));
+
+        run_blocking(&self.db, move |conn| {
+            conn.transaction(|conn| {
+                // locked so that concurrent requests see each other's rotation
+                let stored = remember_tokens::table
+                    .filter(remember_tokens::series.eq(&token_series))
+                    .select(RememberToken::as_select())
+                    .for_update()
+                    .first(conn)
+                    .optional()?;
+                let Some(stored) = stored else {
+                    return Ok(RememberOutcome::Invalid);
+                };
+
+                let now = chrono::Utc::now().naive_utc();
+                if stored.expires_at <= now {
+                    diesel::delete(remember_tokens::table.find(stored.id)).execute(conn)?;
+                    return Ok(RememberOutcome::Invalid);
+                }
+
+                let in_grace_period = now - stored.rotated_at < chrono::TimeDelta::seconds(remember_me::ROTATION_GRACE_SECONDS);
+                let rotated = if stored.token_hash == presented_hash {
+                    let token = remember_me::generate_token();
+                    diesel::update(remember_tokens::table.find(stored.id))
+                        .set((
+                            remember_tokens::token_hash.eq(hash_token(&token)),
+                            remember_tokens::previous_token_hash.eq(&stored.token_hash),
+                            remember_tokens::rotated_at.eq(now),
+                            remember_tokens::expires_at.eq(now + expiry),
+                        ))
+                        .execute(conn)?;
+                    Some(remember_me::cookie_value(&stored.series, &token))
+                } else if stored.previous_token_hash.as_ref() == Some(&presented_hash) && in_grace_period {
+                    None
+                } else {
+                    // someone else used this token: forget every persistent login of the user
+                    diesel::delete(remember_tokens::table.filter(remember_tokens::user_id.eq(stored.user_id)))
+                        .execute(conn)?;
+                    return Ok(RememberOutcome::Stolen(stored.user_id));
+                };
+
+                let user = users::table
+                    .find(stored.user_id)
+                    .filter(users::locked_at.is_null())
+                    .select(User::as_select())
+                    .first(conn)
+                    .optional()?;
+                Ok(match user {
+                    Some(user) => RememberOutcome::Valid(Box::new(user), rotated),
+                    None => RememberOutcome::Invalid,
+                })
+            }).map_err(|e: diesel::result::Error| adapt_app_error(e))
+        }).await
+    }
+
+    /// End the persistent login of a remember-me cookie
+    pub async fn forget_remember_token(&self, value: &str) -> Result<(), AppError> {
+        use crate::schema::remember_tokens::dsl::*;
+        use diesel::prelude::*;
+
+        let Some((token_series, _)) = remember_me::parse_cookie_value(value) else {
+            return Ok(());
+        };
+        let token_series = token_series.to_string();
+        run_blocking(&self.db, move |conn| {
+            diesel::delete(remember_tokens.filter(series.eq(token_series)))
+                .execute(conn)
+                .map_err(adapt_app_error)
+        }).await?;
+
+        Ok(())
+    }
+
+    /// Schedule the erasure of a user's account after the grace period. Their remember-me and API
+    /// tokens are revoked, so that only logging in, which cancels the deletion, gives them access again.
+    pub async fn schedule_account_deletion(&self, user: &User, grace_period: chrono::TimeDelta) -> Result<AccountDeletion, AppError> {
+        use crate::schema::{account_deletions, api_tokens, remember_tokens};
+        use diesel::prelude::*;
+
+        let owner_id = user.id;
+        run_blocking(&self.db, move |conn| {
+            conn.transaction(|conn| {
+                diesel::delete(remember_tokens::table.filter(remember_tokens::user_id.eq(owner_id))).execute(conn)?;
+                diesel::delete(api_tokens::table.filter(api_tokens::user_id.eq(owner_id))).execute(conn)?;
+                diesel::insert_into(account_deletions::table)
+                    .values(&NewAccountDeletion {
+                        user_id: owner_id,
+                        erase_after: chrono::Utc::now().naive_utc() + grace_period,
+                    })
+                    .returning(AccountDeletion::as_returning())
+                    .get_result(conn)
+            }).map_err(|e: diesel::result::Error| adapt_app_error(e))
+        }).await
+    }
+
+    /// Cancel the pending deletion of a user's account, returning whether there was one
+    pub async fn cancel_account_deletion(&self, owner_id: i32) -> Result<bool, AppError> {
+        use crate::schema::account_deletions::dsl::*;
+        use diesel::{dsl::now, prelude::*};
+
+        let canceled = run_blocking(&self.db, move |conn| {
+            diesel::update(account_deletions
+                .filter(user_id.eq(owner_id))
+                .filter(canceled_at.is_null())
+                .filter(erased_at.is_null()))
+                .set(canceled_at.eq(now))
+                .execute(conn)
+                .map_err(adapt_app_error)
+        }).await?;
+
+        Ok(canceled > 0)
+    }
+
+    async fn authenticate_password(&self, credentials: Credentials) -> Result<Option<User>, AppError> {
+        use crate::schema::users::dsl::*;
+        use diesel::prelude::*;
+        
+        let user_email = credentials.email;
+        let user = run_blocking(&self.db, move |conn| {
+            users
+                .filter(email.eq(user_email))
+                .filter(locked_at.is_null())
+                .select(User::as_select())
+                .limit(1)
+                .get_result(conn)
+                .optional()
+                .map_err(adapt_app_error)
+        }).await?;
+
+        spawn_blocking(|| {
+            Ok(user.filter(|user|
+                password_auth::verify_password(
+                    credentials.password,
+                    &String::from(&user.password)
+                ).is_ok()))
+        }).await
+            .map_err(adapt_app_error)?
+    }
+
+    async fn authenticate_passkey(&self, credentials: PasskeyCredentials) -> Result<Option<User>, AppError> {
+        use crate::schema::{users, webauthn_credentials};
+        use diesel::{dsl::now, prelude::*};
+
+        let webauthn = self.webauthn.clone();
+        run_blocking(&self.db, move |conn| {
+            let stored = webauthn_credentials::table
+                .filter(webauthn_credentials::credential_id.eq(&credentials.credential.raw_id))
+                .select(WebauthnCredential::as_select())
+                .first(conn)
+                .optional()
+                .map_err(adapt_app_error)?;
+            let Some(stored) = stored else {
+                return Ok(None);
+            };
+
+            let new_sign_count = match webauthn.finish_authentication(&credentials.challenge, &stored, &credentials.credential) {
+                Ok(count) => count,
+                Err(e) => {
+                    tracing::warn!("Passkey {} rejected: {}", stored.id, e);
+                    return Ok(None);
+                }
+            };
+
+            let user = users::table.find(stored.user_id)
+                .filter(users::locked_at.is_null())
+                .select(User::as_select())
+                .first(conn)
+                .optional()
+                .map_err(adapt_app_error)?;
+            // the user handle is optional in assertions, but must match the credential's owner when present
+            let user = user.filter(|user| match &credentials.credential.response.user_handle {
+                Some(handle) => *handle == Webauthn::user_handle(user),
+                None => true,
+            });
+            let Some(user) = user else {
+                return Ok(None);
+            };
+
+            diesel::update(webauthn_credentials::table.find(stored.id))
+                .set((
+                    webauthn_credentials::sign_count.eq(new_sign_count as i64),
+                    webauthn_credentials::last_used_at.eq(now),
+                ))
+                .execute(conn)
+                .map_err(adapt_app_error)?;
+
+            Ok(Some(user))
+        }).await
+    }
+
+    async fn authenticate_oidc(&self, credentials: OidcCredentials) -> Result<Option<User>, AppError> {
+        let Some(oidc) = &self.oidc else {
+            return Ok(None);
+        };
+
+        match oidc.exchange(credentials.flow, credentials.code, &credentials.state).await {
+            Ok(identity) => self.find_or_provision_oidc_user(identity).await,
+            Err(e) => {
+                tracing::warn!("OpenID Connect login rejected: {}", e);
+                Ok(None)
+            }
+        }
+    }
+
+    /// Find the user linked to an identity. Unknown identities are linked to the user with the same
+    /// e-mail address, who is created if needed, as long as the provider has verified that address.
+    async fn find_or_provision_oidc_user(&self, identity: VerifiedIdentity) -> Result<Option<User>, AppError> {
+        use crate::schema::{oidc_identities, users};
+        use diesel::{dsl::now, prelude::*};
+
+        let (issuer, subject) = (identity.issuer.clone(), identity.subject.clone());
+        let linked = run_blocking(&self.db, move |conn| {
+            oidc_identities::table
+                .inner_join(users::table)
+                .filter(oidc_identities::issuer.eq(issuer))
+                .filter(oidc_identities::subject.eq(subject))
+                .select(User::as_select())
+                .first(conn)
+                .optional()
+                .map_err(adapt_app_error)
+        }).await?;
+        if let Some(user) = linked {
+            return Ok(Some(user).filter(|user| !user.is_locked()));
+        }
+
+        let Some(user_email) = identity.email.filter(|_| identity.email_verified) else {
+            tracing::warn!("OpenID Connect identity {} has no verified e-mail address", identity.subject);
+            return Ok(None);
+        };
+
+        // SSO users get an unusable random password, that can be changed through a reset
+        let mut random_password = [0u8; 32];
+        rand::rngs::OsRng.fill_bytes(&mut random_password);
+        let password_hash = spawn_blocking(move || password_auth::generate_hash(random_password))
+            .await
+            .map_err(adapt_app_error)?;
+
+        run_blocking(&self.db, move |conn| {
+            conn.transaction(|conn| {
+                let user = users::table
+                    .filter(users::email.eq(&user_email))
+                    .select(User::as_select())
+                    .first(conn)
+                    .optional()?;
+                let user = match user {
+                    Some(user) if user.is_locked() => return Ok(None),
+                    Some(user) => user,
+                    None => diesel::insert_into(users::table)
+                        .values((
+                            users::email.eq(&user_email),
+                            users::password.eq(password_hash),
+                            users::confirmed_at.eq(now),
+                        ))
+                        .returning(User::as_returning())
+                        .get_result(conn)?,
+                };
+
+                diesel::insert_into(oidc_identities::table)
+                    .values(&NewOidcIdentity {
+                        user_id: user.id,
+                        issuer: &identity.issuer,
+                        subject: &identity.subject,
+                    })
+                    .execute(conn)?;
+
+                Ok(Some(user))
+            }).map_err(|e: diesel::result::Error| adapt_app_error(e))
+        }).await
     }
 }
 
@@ -21,39 +499,66 @@ impl Backend {
 pub struct Credentials {
     pub email: String,
     pub password: String,
+    /// Keep the user logged in after the session expires
+    #[serde(default)]
+    pub remember_me: bool,
+}
+
+#[derive(Debug)]
+pub enum RememberOutcome {
+    /// The user to log in, with the new cookie value unless the token was already replaced
+    Valid(Box<User>, Option<String>),
+    /// A replaced token was presented: the persistent logins of the user were revoked
+    Stolen(i32),
+    Invalid,
+}
+
+/// A WebAuthn assertion, with the challenge that was issued for it
+#[derive(Clone, Debug)]
+pub struct PasskeyCredentials {
+    pub challenge: Challenge,
+    pub credential: PublicKeyCredential,
+}
+
+/// An authorization code returned by the OpenID Connect provider, with the flow that requested it
+#[derive(Clone, Debug)]
+pub struct OidcCredentials {
+    pub flow: OidcFlow,
+    pub code: String,
+    pub state: String,
+}
+
+#[derive(Clone, Debug)]
+pub enum AuthCredentials {
+    Password(Credentials),
+    Passkey(PasskeyCredentials),
+    Oidc(OidcCredentials),
 }
 
 #[async_trait]
 impl AuthnBackend for Backend {
     type User = User;
-    type Credentials = Credentials;
+    type Credentials = AuthCredentials;
     type Error = AppError;
 
     async fn authenticate(
         &self,
         credentials: Self::Credentials,
     ) -> Result<Option<Self::User>, Self::Error> {
-        use crate::schema::users::dsl::*;
-        use diesel::prelude::*;
-        
-        let conn = &mut self.db.get().map_err(adapt_app_error)?;
-
-        let user = users
-            .filter(email.eq(credentials.email))
-            .select(User::as_select())
-            .limit(1)
-            .get_result(conn)
-            .optional()
-            .map_err(adapt_app_error)?;
+        let (method, result) = match credentials {
+            AuthCredentials::Password(credentials) => ("password", self.authenticate_password(credentials).await),
+            AuthCredentials::Passkey(credentials) => ("passkey", self.authenticate_passkey(credentials).await),
+            AuthCredentials::Oidc(credentials) => ("oidc", self.authenticate_oidc(credentials).await),
+        };
+        record_login(method, &result);
 
-        tokio::task::spawn_blocking(|| {
-            Ok(user.filter(|user|
-                password_auth::verify_password(
-                    credentials.password,
-                    &String::from(&user.password)
-                ).is_ok()))
-        }).await
-            .map_err(adapt_app_error)?
+        // logging in cancels a pending deletion of the account
+        if let Ok(Some(user)) = &result {
+            if self.cancel_account_deletion(user.id).await? {
+                tracing::info!(user_id = user.id, "account deletion canceled by logging in");
+            }
+        }
+        result
     }
 
     async fn get_user(
@@ -63,15 +568,42 @@ impl AuthnBackend for Backend {
         use crate::schema::users::dsl::*;
         use diesel::prelude::*;
 
-        let conn = &mut self.db.get().map_err(adapt_app_error)?;
+        // locked users are logged out of their existing sessions
+        let user_id = *user_id;
+        run_blocking(&self.db, move |conn| {
+            users.find(user_id)
+                .filter(locked_at.is_null())
+                .select(User::as_select())
+                .first(conn)
+                .optional()
+                .map_err(adapt_app_error)
+        }).await
+    }
+}
 
-        let user = users.find(user_id)
-            .select(User::as_select())
-            .first(conn)
-            .optional()
-            .map_err(adapt_app_error)?;
-        
-        Ok(user)
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Permission {
+    ManageUsers,
+}
+
+impl Role {
+    pub fn permissions(&self) -> HashSet<Permission> {
+        match self {
+            Role::User => HashSet::new(),
+            Role::Admin => HashSet::from([Permission::ManageUsers]),
+        }
+    }
+}
+
+#[async_trait]
+impl AuthzBackend for Backend {
+    type Permission = Permission;
+
+    async fn get_user_permissions(
+        &self,
+        user: &Self::User,
+    ) -> Result<HashSet<Self::Permission>, Self::Error> {
+        Ok(user.role().permissions())
     }
 }
 
@@ -84,12 +616,21 @@ mod tests {
     use crate::{Config, db::TestDb};
     use crate::db::seeds;
     use crate::models::NewUser;
+    use crate::oidc::mock_provider::MockProvider;
+    use crate::webauthn::soft_authenticator::SoftAuthenticator;
+
+    const RP_ID: &str = "localhost";
+    const ORIGIN: &str = "http://localhost:7878";
 
     fn get_test_db() -> TestDb {
         let config = Config::new().unwrap();
         TestDb::new(&config)
     }
 
+    fn get_backend(pool: Pool<ConnectionManager<PgConnection>>) -> Backend {
+        Backend::new(pool, Webauthn::new(RP_ID, "Anthère", ORIGIN))
+    }
+
     #[tokio::test]
     async fn test_authenticate() {
         let db = get_test_db();
@@ -97,12 +638,13 @@ mod tests {
             let seed_users = seeds::users::users();
             let user = seed_users.first().unwrap();
 
-            let backend = Backend::new(pool);
+            let backend = get_backend(pool);
             let creds = Credentials {
                 email: user.email.to_string(),
                 password: "passw0rd".to_string(),
+                remember_me: false,
             };
-            let res = backend.authenticate(creds).await.unwrap();
+            let res = backend.authenticate(AuthCredentials::Password(creds)).await.unwrap();
             assert!(res.is_some());
 
             let res = res.unwrap();
@@ -127,9 +669,290 @@ mod tests {
                 .get_result(conn)
                 .unwrap();
 
-            let backend = Backend::new(pool);
+            let backend = get_backend(pool);
             let res = backend.get_user(&user.id).await;
             assert!(res.is_ok());
         }.boxed()).await;
     }
+
+    #[tokio::test]
+    async fn test_authenticate_passkey() {
+        let db = get_test_db();
+        db.run_test(|pool| async move {
+            use crate::schema::users::dsl::*;
+            use diesel::prelude::*;
+
+            let conn = &mut pool.get().unwrap();
+            let user: User = users
+                .filter(email.eq("miles.davis@trumpet.com"))
+                .select(User::as_select())
+                .first(conn)
+                .unwrap();
+
+            let backend = get_backend(pool);
+            let mut authenticator = SoftAuthenticator::new(RP_ID, ORIGIN);
+            let (options, challenge) = backend.webauthn().start_registration(&user, &[]);
+            let registered = backend.webauthn()
+                .finish_registration(&challenge, &authenticator.register(&options))
+                .unwrap();
+            backend.add_passkey(&user, "YubiKey", registered).await.unwrap();
+            assert_eq!(backend.passkeys(&user).await.unwrap().len(), 1);
+
+            let (options, challenge) = backend.webauthn().start_authentication();
+            let credential = authenticator.authenticate(&options);
+            let creds = AuthCredentials::Passkey(PasskeyCredentials { challenge, credential: credential.clone() });
+            let res = backend.authenticate(creds).await.unwrap();
+            assert_eq!(res.map(|u| u.id), Some(user.id));
+
+            // replaying the same assertion must fail, as the challenge was consumed
+            let (_, challenge) = backend.webauthn().start_authentication();
+            let creds = AuthCredentials::Passkey(PasskeyCredentials { challenge, credential });
+            assert!(backend.authenticate(creds).await.unwrap().is_none());
+        }.boxed()).await;
+    }
+
+    #[tokio::test]
+    async fn test_authenticate_api_token() {
+        let db = get_test_db();
+        db.run_test(|pool| async move {
+            use crate::schema::users::dsl::*;
+            use diesel::prelude::*;
+
+            let conn = &mut pool.get().unwrap();
+            let user: User = users
+                .filter(email.eq("marcus.miller@bass.com"))
+                .select(User::as_select())
+                .first(conn)
+                .unwrap();
+
+            let backend = get_backend(pool);
+            let (token, secret) = backend.create_api_token(&user, "export script", &[Scope::Read], None).await.unwrap();
+            assert_eq!(token.scopes(), vec![Scope::Read]);
+            assert_ne!(token.token_hash, secret.as_bytes());
+
+            let (found_user, found_token) = backend.authenticate_api_token(&secret).await.unwrap().unwrap();
+            assert_eq!(found_user.id, user.id);
+            assert!(found_token.last_used_at.is_none());
+            let tokens = backend.api_tokens(&user).await.unwrap();
+            assert!(tokens[0].last_used_at.is_some());
+
+            assert!(backend.authenticate_api_token("anthere_pat_invalid").await.unwrap().is_none());
+
+            assert!(backend.revoke_api_token(&user, token.id).await.unwrap());
+            assert!(backend.authenticate_api_token(&secret).await.unwrap().is_none());
+        }.boxed()).await;
+    }
+
+    #[tokio::test]
+    async fn test_authenticate_expired_api_token() {
+        let db = get_test_db();
+        db.run_test(|pool| async move {
+            use crate::schema::users::dsl::*;
+            use diesel::prelude::*;
+
+            let conn = &mut pool.get().unwrap();
+            let user: User = users
+                .filter(email.eq("marcus.miller@bass.com"))
+                .select(User::as_select())
+                .first(conn)
+                .unwrap();
+
+            let backend = get_backend(pool);
+            let yesterday = chrono::Utc::now().naive_utc() - chrono::Duration::days(1);
+            let (_, secret) = backend.create_api_token(&user, "old", &[Scope::Read], Some(yesterday)).await.unwrap();
+            assert!(backend.authenticate_api_token(&secret).await.unwrap().is_none());
+        }.boxed()).await;
+    }
+
+    #[tokio::test]
+    async fn test_account_deletion_revokes_api_tokens() {
+        let db = get_test_db();
+        db.run_test(|pool| async move {
+            use crate::schema::users::dsl::*;
+            use diesel::prelude::*;
+
+            let conn = &mut pool.get().unwrap();
+            let user: User = users
+                .filter(email.eq("marcus.miller@bass.com"))
+                .select(User::as_select())
+                .first(conn)
+                .unwrap();
+
+            let backend = get_backend(pool);
+            let (_, secret) = backend.create_api_token(&user, "export script", &[Scope::Read], None).await.unwrap();
+            backend.schedule_account_deletion(&user, chrono::TimeDelta::days(30)).await.unwrap();
+
+            assert!(backend.authenticate_api_token(&secret).await.unwrap().is_none());
+            assert!(backend.api_tokens(&user).await.unwrap().is_empty());
+        }.boxed()).await;
+    }
+
+    #[tokio::test]
+    async fn test_remember_token() {
+        let db = get_test_db();
+        db.run_test(|pool| async move {
+            use crate::schema::{remember_tokens, users::dsl::*};
+            use diesel::prelude::*;
+
+            let conn = &mut pool.get().unwrap();
+            let user: User = users
+                .filter(email.eq("marcus.miller@bass.com"))
+                .select(User::as_select())
+                .first(conn)
+                .unwrap();
+
+            let backend = get_backend(pool.clone());
+            let expiry = chrono::TimeDelta::days(30);
+            let first = backend.remember_user(&user, expiry).await.unwrap();
+            let other_login = backend.remember_user(&user, expiry).await.unwrap();
+
+            let RememberOutcome::Valid(found, Some(second)) = backend.authenticate_remember_token(&first, expiry).await.unwrap() else {
+                panic!("the token should be valid and replaced");
+            };
+            assert_eq!(found.id, user.id);
+            assert_eq!(second.split('.').next(), first.split('.').next());
+
+            // a concurrent request with the replaced token is accepted, without replacing it again
+            assert!(matches!(backend.authenticate_remember_token(&first, expiry).await.unwrap(), RememberOutcome::Valid(_, None)));
+            assert!(matches!(backend.authenticate_remember_token("invalid", expiry).await.unwrap(), RememberOutcome::Invalid));
+
+            // but not after the grace period, when it means the cookie was stolen
+            diesel::update(remember_tokens::table)
+                .set(remember_tokens::rotated_at.eq(chrono::Utc::now().naive_utc() - chrono::TimeDelta::minutes(5)))
+                .execute(conn)
+                .unwrap();
+            assert!(matches!(backend.authenticate_remember_token(&first, expiry).await.unwrap(), RememberOutcome::Stolen(owner) if owner == user.id));
+            assert!(matches!(backend.authenticate_remember_token(&second, expiry).await.unwrap(), RememberOutcome::Invalid));
+            assert!(matches!(backend.authenticate_remember_token(&other_login, expiry).await.unwrap(), RememberOutcome::Invalid));
+        }.boxed()).await;
+    }
+
+    #[tokio::test]
+    async fn test_forget_remember_token() {
+        let db = get_test_db();
+        db.run_test(|pool| async move {
+            use crate::schema::users::dsl::*;
+            use diesel::prelude::*;
+
+            let conn = &mut pool.get().unwrap();
+            let user: User = users
+                .filter(email.eq("marcus.miller@bass.com"))
+                .select(User::as_select())
+                .first(conn)
+                .unwrap();
+
+            let backend = get_backend(pool);
+            let expiry = chrono::TimeDelta::days(30);
+
+            let value = backend.remember_user(&user, expiry).await.unwrap();
+            backend.forget_remember_token(&value).await.unwrap();
+            assert!(matches!(backend.authenticate_remember_token(&value, expiry).await.unwrap(), RememberOutcome::Invalid));
+
+            let expired = backend.remember_user(&user, chrono::TimeDelta::seconds(-1)).await.unwrap();
+            assert!(matches!(backend.authenticate_remember_token(&expired, expiry).await.unwrap(), RememberOutcome::Invalid));
+        }.boxed()).await;
+    }
+
+    async fn oidc_login(backend: &Backend, provider: &MockProvider, subject: &str, user_email: &str, verified: bool) -> Option<User> {
+        let oidc = backend.oidc().unwrap();
+        let (url, flow) = oidc.authorize();
+        let nonce = url.query_pairs().find(|(k, _)| k == "nonce").unwrap().1.to_string();
+        let state = url.query_pairs().find(|(k, _)| k == "state").unwrap().1.to_string();
+        provider.issue(subject, Some(user_email), verified, &openidconnect::Nonce::new(nonce)).await;
+
+        let creds = AuthCredentials::Oidc(OidcCredentials { flow, code: provider.code(), state });
+        backend.authenticate(creds).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_authenticate_oidc() {
+        let db = get_test_db();
+        let provider = std::sync::Arc::new(MockProvider::start().await);
+        db.run_test(|pool| {
+            let provider = provider.clone();
+            async move {
+                use crate::schema::users::dsl::*;
+                use diesel::prelude::*;
+
+                let oidc = Oidc::discover(&provider.config()).await.unwrap();
+                let backend = get_backend(pool.clone()).with_oidc(oidc);
+
+                // unknown e-mail: the user is provisioned
+                let user = oidc_login(&backend, &provider, "jdoe", "john.doe@example.com", true).await.unwrap();
+                assert_eq!(user.email, "john.doe@example.com");
+                assert!(user.confirmed_at.is_some());
+
+                // known e-mail: the identity is linked to the existing user
+                let user = oidc_login(&backend, &provider, "mdavis", "miles.davis@trumpet.com", true).await.unwrap();
+                let conn = &mut pool.get().unwrap();
+                let seeded: User = users
+                    .filter(email.eq("miles.davis@trumpet.com"))
+                    .select(User::as_select())
+                    .first(conn)
+                    .unwrap();
+                assert_eq!(user.id, seeded.id);
+
+                // linked identities are found by subject, even if the e-mail changed at the provider
+                let user = oidc_login(&backend, &provider, "mdavis", "miles@example.com", false).await.unwrap();
+                assert_eq!(user.id, seeded.id);
+
+                // unverified e-mail addresses are never trusted to link accounts
+                let res = oidc_login(&backend, &provider, "mmiller", "marcus.miller@bass.com", false).await;
+                assert!(res.is_none());
+            }.boxed()
+        }).await;
+    }
+
+    #[tokio::test]
+    async fn test_permissions() {
+        let db = get_test_db();
+        db.run_test(|pool| async move {
+            use crate::schema::users::dsl::*;
+            use diesel::prelude::*;
+
+            let conn = &mut pool.get().unwrap();
+            let admin: User = diesel::update(users.filter(email.eq("miles.davis@trumpet.com")))
+                .set(role.eq(Role::Admin.to_string()))
+                .returning(User::as_returning())
+                .get_result(conn)
+                .unwrap();
+            let user: User = users
+                .filter(email.eq("marcus.miller@bass.com"))
+                .select(User::as_select())
+                .first(conn)
+                .unwrap();
+
+            let backend = get_backend(pool);
+            assert!(backend.has_perm(&admin, Permission::ManageUsers).await.unwrap());
+            assert!(!backend.has_perm(&user, Permission::ManageUsers).await.unwrap());
+        }.boxed()).await;
+    }
+
+    #[tokio::test]
+    async fn test_locked_user() {
+        let db = get_test_db();
+        db.run_test(|pool| async move {
+            use crate::schema::users::dsl::*;
+            use diesel::{dsl::now, prelude::*};
+
+            let conn = &mut pool.get().unwrap();
+            let user: User = diesel::update(users.filter(email.eq("miles.davis@trumpet.com")))
+                .set(locked_at.eq(now))
+                .returning(User::as_returning())
+                .get_result(conn)
+                .unwrap();
+
+            let backend = get_backend(pool);
+            let creds = Credentials {
+                email: user.email.to_string(),
+                password: "passw0rd".to_string(),
+                remember_me: false,
+            };
+            assert!(backend.authenticate(AuthCredentials::Password(creds)).await.unwrap().is_none());
+            assert!(backend.get_user(&user.id).await.unwrap().is_none());
+
+            let (_, secret) = backend.create_api_token(&user, "script", &[Scope::Read], None).await.unwrap();
+            assert!(backend.authenticate_api_token(&secret).await.unwrap().is_none());
+        }.boxed()).await;
+    }
 }
\ No newline at end of file
@@ -0,0 +1,187 @@
+//! In-process LRU cache in front of a session store, for single-node deployments: loads only hit
+//! the inner store on a miss, and writes go through to it. Hits and misses are counted in the
+//! Prometheus metrics.
+
+use std::num::NonZeroUsize;
+use std::sync::{Arc, Mutex, atomic::{AtomicU64, Ordering}};
+use async_trait::async_trait;
+use lru::LruCache;
+use serde::Serialize;
+use time::OffsetDateTime;
+use tower_sessions::{
+    session::{Id, Record},
+    session_store, SessionStore,
+};
+use crate::prometheus::{SESSION_CACHE_HITS, SESSION_CACHE_MISSES};
+use crate::store::record_user_id;
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub entries: usize,
+}
+
+#[derive(Clone, Debug)]
+pub struct CachingStore<S> {
+    inner: S,
+    cache: Arc<Mutex<LruCache<Id, Record>>>,
+    hits: Arc<AtomicU64>,
+    misses: Arc<AtomicU64>,
+}
+
+impl<S> CachingStore<S> {
+    pub fn new(inner: S, capacity: NonZeroUsize) -> Self {
+        Self {
+            inner,
+            cache: Arc::new(Mutex::new(LruCache::new(capacity))),
+            hits: Arc::new(AtomicU64::new(0)),
+            misses: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            entries: self.cache.lock().unwrap().len(),
+        }
+    }
+
+    /// Drop the cached sessions of a user, whose sessions are being deleted from the inner store
+    pub fn evict_user(&self, user_id: i32, except: Option<&Id>) {
+        let mut cache = self.cache.lock().unwrap();
+        let evicted = cache.iter()
+            .filter(|(id, record)| Some(*id) != except && record_user_id(record) == Some(user_id))
+            .map(|(id, _)| *id)
+            .collect::<Vec<_>>();
+        for id in evicted {
+            cache.pop(&id);
+        }
+    }
+
+    fn cache(&self, record: &Record) {
+        self.cache.lock().unwrap().put(record.id, record.clone());
+    }
+}
+
+#[async_trait]
+impl<S: SessionStore> SessionStore for CachingStore<S> {
+    async fn create(&self, record: &mut Record) -> session_store::Result<()> {
+        self.inner.create(record).await?;
+        self.cache(record);
+        Ok(())
+    }
+
+    async fn save(&self, record: &Record) -> session_store::Result<()> {
+        self.inner.save(record).await?;
+        self.cache(record);
+        Ok(())
+    }
+
+    async fn load(&self, session_id: &Id) -> session_store::Result<Option<Record>> {
+        let cached = self.cache.lock().unwrap().get(session_id).cloned();
+        match cached {
+            Some(record) if record.expiry_date > OffsetDateTime::now_utc() => {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                metrics::counter!(SESSION_CACHE_HITS).increment(1);
+                return Ok(Some(record));
+            }
+            Some(_) => {
+                self.cache.lock().unwrap().pop(session_id);
+            }
+            None => {}
+        }
+
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        metrics::counter!(SESSION_CACHE_MISSES).increment(1);
+        let record = self.inner.load(session_id).await?;
+        if let Some(record) = &record {
+            self.cache(record);
+        }
+        Ok(record)
+    }
+
+    async fn delete(&self, session_id: &Id) -> session_store::Result<()> {
+        self.cache.lock().unwrap().pop(session_id);
+        self.inner.delete(session_id).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::FutureExt;
+    use metrics_exporter_prometheus::PrometheusBuilder;
+    use time::Duration;
+    use crate::Config;
+    use crate::db::TestDb;
+    use crate::store::{AUTH_DATA_KEY, PgStore};
+    use super::*;
+
+    fn get_db_pool() -> TestDb {
+        let config = Config::new().unwrap();
+        TestDb::new(&config)
+    }
+
+    fn record(user_id: i32) -> Record {
+        Record {
+            id: Default::default(),
+            data: [(AUTH_DATA_KEY.to_string(), serde_json::json!({ "user_id": user_id, "auth_hash": [] }))].into(),
+            expiry_date: OffsetDateTime::now_utc() + Duration::minutes(30),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_load_hits_cache() {
+        let recorder = PrometheusBuilder::new().build_recorder();
+        let handle = recorder.handle();
+        let _guard = metrics::set_default_local_recorder(&recorder);
+
+        let db = get_db_pool();
+        db.run_test(|pool| async move {
+            let pg_store = PgStore::new(pool);
+            let store = CachingStore::new(pg_store.clone(), NonZeroUsize::new(1).unwrap());
+
+            let mut first = record(1);
+            store.create(&mut first).await.unwrap();
+            assert_eq!(store.load(&first.id).await.unwrap(), Some(first.clone()));
+            assert_eq!(store.stats(), CacheStats { hits: 1, misses: 0, entries: 1 });
+
+            // evicts the first session, which is then loaded from the database
+            let mut second = record(1);
+            store.create(&mut second).await.unwrap();
+            assert_eq!(store.load(&first.id).await.unwrap(), Some(first.clone()));
+            assert_eq!(store.stats(), CacheStats { hits: 1, misses: 1, entries: 1 });
+
+            // writes go through
+            assert_eq!(pg_store.load(&second.id).await.unwrap(), Some(second.clone()));
+            store.delete(&first.id).await.unwrap();
+            assert_eq!(pg_store.load(&first.id).await.unwrap(), None);
+            assert_eq!(store.load(&first.id).await.unwrap(), None);
+        }.boxed()).await;
+
+        let rendered = handle.render();
+        assert!(rendered.contains("session_cache_hits_total 1"));
+        assert!(rendered.contains("session_cache_misses_total 2"));
+    }
+
+    #[tokio::test]
+    async fn test_evict_user() {
+        let db = get_db_pool();
+        db.run_test(|pool| async move {
+            let store = CachingStore::new(PgStore::new(pool), NonZeroUsize::new(10).unwrap());
+
+            let (mut kept, mut evicted, mut other) = (record(1), record(1), record(2));
+            for record in [&mut kept, &mut evicted, &mut other] {
+                store.create(record).await.unwrap();
+            }
+
+            store.evict_user(1, Some(&kept.id));
+            assert_eq!(store.stats().entries, 2);
+        }.boxed()).await;
+    }
+}
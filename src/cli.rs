@@ -0,0 +1,315 @@
+//! The command line: without a command it starts the server, and its commands let operators
+//! manage an instance without SQL
+
+use std::io::BufRead;
+use clap::{Parser, Subcommand};
+use diesel::PgConnection;
+use tower_sessions::ExpiredDeletion;
+use crate::config::{Config, SessionStoreConfig};
+use crate::db::migrate;
+use crate::get_connection_pool;
+use crate::models::{Role, User};
+use crate::redis_store::RedisStore;
+use crate::store::{AppStore, PgStore};
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+#[derive(Debug, Parser)]
+#[command(version, about = "The Anthère server, and its admin commands")]
+pub struct Cli {
+    /// Print the effective configuration, with its secrets redacted
+    #[arg(long)]
+    pub print_config: bool,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Start the server, the default
+    Serve,
+    /// Print a new random key, e.g. for SESSION_KEY
+    GenerateKey,
+    /// Apply the pending database migrations
+    Migrate {
+        #[command(subcommand)]
+        action: Option<MigrateAction>,
+    },
+    /// Create a user, whose password is read from stdin
+    CreateUser {
+        email: String,
+        #[arg(long, default_value_t = Role::User)]
+        role: Role,
+        /// Confirm the user's email right away
+        #[arg(long)]
+        confirmed: bool,
+    },
+    /// Set the password of a user, read from stdin, which signs them out
+    ResetPassword { email: String },
+    /// Confirm the email of a user
+    ConfirmUser { email: String },
+    /// List the users, with their role and status
+    ListUsers,
+    /// Import an archive for a user, not supported yet
+    Import {
+        file: std::path::PathBuf,
+        #[arg(long)]
+        user: String,
+    },
+    /// Rebuild the archive search index, not supported yet
+    Reindex,
+    /// Delete the expired sessions, or every session of a user
+    PurgeSessions {
+        /// Email of the user to sign out everywhere, remember-me cookies included
+        #[arg(long)]
+        user: Option<String>,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum MigrateAction {
+    /// List the migrations, and whether they are applied
+    Status,
+    /// Revert the last applied migration
+    Revert,
+}
+
+/// Run an admin command
+pub async fn run(command: Command, config: &Config) -> Result<()> {
+    if matches!(command, Command::Import { .. } | Command::Reindex) {
+        return Err("not supported: this version has no archives to import or index".into());
+    }
+
+    let db = get_connection_pool(&config.database_url);
+    let conn = &mut db.get()?;
+
+    match command {
+        Command::Serve | Command::GenerateKey => unreachable!("handled by main"),
+        Command::Migrate { action: None } => {
+            let applied = migrate::run_pending(conn)?;
+            println!("{} migration(s) applied", applied.len());
+            for version in applied {
+                println!("  {}", version);
+            }
+        }
+        Command::Migrate { action: Some(MigrateAction::Status) } => {
+            for migration in migrate::status(conn)? {
+                println!("{}  {}", if migration.applied { "applied" } else { "pending" }, migration.version);
+            }
+        }
+        Command::Migrate { action: Some(MigrateAction::Revert) } => {
+            println!("reverted {}", migrate::revert_last(conn)?);
+        }
+        Command::CreateUser { email, role, confirmed } => {
+            let user = create_user(conn, &email, &read_password()?, role, confirmed)?;
+            println!("created user {} ({})", user.id, user.email);
+        }
+        Command::ResetPassword { email } => {
+            let user = reset_password(conn, &email, &read_password()?)?;
+            println!("password of {} reset", user.email);
+        }
+        Command::ConfirmUser { email } => {
+            let user = confirm_user(conn, &email)?;
+            println!("{} confirmed", user.email);
+        }
+        Command::ListUsers => {
+            println!("{:>6}  {:<40}  {:<5}  {:<9}  {:<6}  created", "id", "email", "role", "confirmed", "locked");
+            for user in list_users(conn)? {
+                println!(
+                    "{:>6}  {:<40}  {:<5}  {:<9}  {:<6}  {}",
+                    user.id,
+                    user.email,
+                    user.role(),
+                    if user.confirmed_at.is_some() { "yes" } else { "no" },
+                    if user.is_locked() { "yes" } else { "no" },
+                    user.created_at.format("%Y-%m-%d %H:%M"),
+                );
+            }
+        }
+        Command::Import { .. } | Command::Reindex => unreachable!("rejected above"),
+        Command::PurgeSessions { user: None } => match &config.session_store {
+            SessionStoreConfig::Postgres { .. } => {
+                PgStore::new(db.clone()).delete_expired().await?;
+                println!("expired sessions deleted");
+            }
+            SessionStoreConfig::Redis { .. } => println!("sessions expire on their own in Redis"),
+        },
+        Command::PurgeSessions { user: Some(email) } => {
+            let user = find_user(conn, &email)?;
+            let store = match &config.session_store {
+                SessionStoreConfig::Postgres { .. } => AppStore::Postgres(PgStore::new(db.clone())),
+                SessionStoreConfig::Redis { url } => AppStore::Redis(RedisStore::connect(url).await?),
+            };
+            let deleted = store.delete_user_sessions(user.id, None).await?;
+            forget_user(conn, user.id)?;
+            println!("{} session(s) of {} deleted", deleted, user.email);
+            // the running server's cache is out of reach of this process
+            if matches!(config.session_store, SessionStoreConfig::Postgres { cache_size: Some(_) }) {
+                eprintln!(
+                    "warning: sessions cached by a running server stay valid until it restarts; \
+                    DELETE /admin/users/{}/sessions revokes them without a restart",
+                    user.id,
+                );
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// A password, read from the first line of stdin
+fn read_password() -> Result<String> {
+    eprint!("password: ");
+    let mut password = String::new();
+    std::io::stdin().lock().read_line(&mut password)?;
+    let password = password.trim_end_matches(['\r', '\n']);
+    if password.is_empty() {
+        return Err("the password must not be empty".into());
+    }
+    Ok(password.to_string())
+}
+
+fn find_user(conn: &mut PgConnection, email: &str) -> Result<User> {
+    use crate::schema::users;
+    use diesel::prelude::*;
+
+    users::table
+        .filter(users::email.eq(email))
+        .select(User::as_select())
+        .first(conn)
+        .optional()?
+        .ok_or_else(|| format!("no user with email {}", email).into())
+}
+
+pub fn create_user(conn: &mut PgConnection, email: &str, password: &str, role: Role, confirmed: bool) -> Result<User> {
+    use crate::schema::users;
+    use diesel::{dsl::now, prelude::*};
+
+    let confirmed_at = confirmed.then(|| chrono::Utc::now().naive_utc());
+    let user = diesel::insert_into(users::table)
+        .values((
+            users::email.eq(email),
+            users::password.eq(password_auth::generate_hash(password)),
+            users::role.eq(role.to_string()),
+            users::confirmed_at.eq(confirmed_at),
+            users::updated_at.eq(now),
+        ))
+        .returning(User::as_returning())
+        .get_result(conn)?;
+    Ok(user)
+}
+
+/// Changing the password invalidates the user's sessions, and their remember-me tokens are revoked
+pub fn reset_password(conn: &mut PgConnection, email: &str, password: &str) -> Result<User> {
+    use crate::schema::users;
+    use diesel::{dsl::now, prelude::*};
+
+    let user = find_user(conn, email)?;
+    let hash = password_auth::generate_hash(password);
+    let user = conn.transaction(|conn| {
+        forget_user(conn, user.id)?;
+        diesel::update(users::table.find(user.id))
+            .set((
+                users::password.eq(hash),
+                users::reset_password_token.eq(None::<String>),
+                users::reset_password_sent_at.eq(None::<chrono::NaiveDateTime>),
+                users::updated_at.eq(now),
+            ))
+            .returning(User::as_returning())
+            .get_result(conn)
+    })?;
+    Ok(user)
+}
+
+/// An already confirmed user keeps their original confirmation date
+pub fn confirm_user(conn: &mut PgConnection, email: &str) -> Result<User> {
+    use crate::schema::users;
+    use diesel::{dsl::now, prelude::*};
+
+    let user = find_user(conn, email)?;
+    if user.confirmed_at.is_some() {
+        return Ok(user);
+    }
+    let user = diesel::update(users::table.find(user.id))
+        .set((users::confirmed_at.eq(now), users::confirmation_token.eq(None::<String>)))
+        .returning(User::as_returning())
+        .get_result(conn)?;
+    Ok(user)
+}
+
+pub fn list_users(conn: &mut PgConnection) -> Result<Vec<User>> {
+    use crate::schema::users;
+    use diesel::prelude::*;
+
+    Ok(users::table.select(User::as_select()).order(users::id.asc()).load(conn)?)
+}
+
+/// Revoke the remember-me tokens of a user
+fn forget_user(conn: &mut PgConnection, user_id: i32) -> diesel::QueryResult<usize> {
+    use crate::schema::remember_tokens;
+    use diesel::prelude::*;
+
+    diesel::delete(remember_tokens::table.filter(remember_tokens::user_id.eq(user_id))).execute(conn)
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::FutureExt;
+    use crate::db::TestDb;
+    use super::*;
+
+    fn get_db_pool() -> TestDb {
+        let config = Config::new().unwrap();
+        TestDb::new(&config)
+    }
+
+    #[test]
+    fn test_parse() {
+        let cli = Cli::try_parse_from(["anthere", "create-user", "miles.davis@trumpet.com", "--role", "admin"]).unwrap();
+        assert!(matches!(
+            cli.command,
+            Some(Command::CreateUser { email, role: Role::Admin, confirmed: false }) if email == "miles.davis@trumpet.com"
+        ));
+        assert!(Cli::try_parse_from(["anthere", "create-user", "miles.davis@trumpet.com", "--role", "root"]).is_err());
+        assert!(Cli::try_parse_from(["anthere", "--print-config"]).unwrap().command.is_none());
+        assert!(matches!(
+            Cli::try_parse_from(["anthere", "migrate", "revert"]).unwrap().command,
+            Some(Command::Migrate { action: Some(MigrateAction::Revert) })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_unsupported_commands() {
+        let config = Config::new().unwrap();
+        for args in [&["anthere", "reindex"][..], &["anthere", "import", "archive.zip", "--user", "miles.davis@trumpet.com"]] {
+            let command = Cli::try_parse_from(args).unwrap().command.unwrap();
+            let error = run(command, &config).await.unwrap_err();
+            assert!(error.to_string().starts_with("not supported"));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_user_commands() {
+        let db = get_db_pool();
+        db.run_test(|pool| async move {
+            let conn = &mut pool.get().unwrap();
+
+            let user = create_user(conn, "herbie.hancock@piano.com", "passw0rd", Role::Admin, false).unwrap();
+            assert_eq!(user.role(), Role::Admin);
+            assert!(user.confirmed_at.is_none());
+            assert!(create_user(conn, "herbie.hancock@piano.com", "passw0rd", Role::User, false).is_err());
+
+            let confirmed = confirm_user(conn, "herbie.hancock@piano.com").unwrap();
+            assert!(confirmed.confirmed_at.is_some());
+            assert_eq!(confirm_user(conn, "herbie.hancock@piano.com").unwrap().confirmed_at, confirmed.confirmed_at);
+
+            let reset = reset_password(conn, "marcus.miller@bass.com", "s0 what").unwrap();
+            assert!(password_auth::verify_password("s0 what", &reset.password).is_ok());
+            assert!(reset_password(conn, "wayne.shorter@sax.com", "s0 what").unwrap_err().to_string().contains("no user"));
+
+            let emails = list_users(conn).unwrap().into_iter().map(|user| user.email).collect::<Vec<_>>();
+            assert_eq!(emails, vec!["miles.davis@trumpet.com", "marcus.miller@bass.com", "herbie.hancock@piano.com"]);
+        }.boxed()).await;
+    }
+}
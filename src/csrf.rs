@@ -1,26 +1,219 @@
+//! CSRF protection by double submit: the CSRF cookie set by `CsrfLayer` holds a secret, and
+//! state-changing requests must send back its authenticity token, either in the `X-CSRF-Token`
+//! header or in the `authenticity_token` form field. Other sites can read neither of them.
+
+use axum::body::{Body, to_bytes};
+use axum::extract::{Request, State};
+use axum::http::header::{AUTHORIZATION, CONTENT_TYPE};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
 use axum_csrf::CsrfToken;
-use tower_sessions_core::Session;
-use axum::Form;
+use serde_json::json;
+use crate::errors::AppError;
+
+pub const CSRF_HEADER: &str = "x-csrf-token";
+pub const CSRF_FORM_FIELD: &str = "authenticity_token";
+
+/// Forms are buffered to read their token, up to this size
+const MAX_FORM_SIZE: usize = 1024 * 1024;
+
+/// API routes, where `ApiUser` authenticates a bearer token without falling back to the session
+const API_PREFIX: &str = "/api/";
+
+/// Requests not checked for a CSRF token
+#[derive(Clone, Debug, Default)]
+pub struct CsrfExemptions {
+    /// Path prefixes, e.g. of webhooks authenticated by a signature
+    paths: Vec<String>,
+}
+
+impl CsrfExemptions {
+    pub fn new(paths: Vec<String>) -> Self {
+        Self { paths }
+    }
+
+    fn is_exempt(&self, req: &Request) -> bool {
+        // access tokens are not sent by browsers on their own, unlike cookies, so API calls
+        // authenticated by one cannot be forged. Other routes authenticate by the session cookie
+        // only, so a bearer header proves nothing there.
+        let path = req.uri().path();
+        let bearer = req.headers().get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|value| value.len() > 7 && value[..7].eq_ignore_ascii_case("bearer "));
 
-pub struct CsrfProtected {
-    authenticity_token: String
+        let api_call = bearer && path.starts_with(API_PREFIX);
+
+        api_call || self.paths.iter().any(|prefix| path.starts_with(prefix.as_str()))
+    }
 }
 
-pub async fn check_key(token: CsrfToken, session: Session, Form(payload): Form<CsrfProtected>, callback: Box<dyn Fn()>) {
-    let authenticity_token: Option<String> = session.get("authenticity_token").await.unwrap_or_default();
-
-    match authenticity_token {
-        None => { tracing::warn!("Token was not set"); },
-        Some(authenticity_token) => {
-            if let Err(_) = token.verify(&payload.authenticity_token) {
-                tracing::warn!("Token is invalid");
-            } else if let Err(_) = token.verify(&authenticity_token) {
-                tracing::warn!("Modification of both Cookie/token OR a replay attack occurred");
-            } else {
-                // we remove it to only allow one post per generated token.
-                session.remove::<String>("authenticity_token").await.unwrap();
-                callback();
-            }
+/// Middleware, to run inside `CsrfLayer`, rejecting state-changing requests without a valid
+/// authenticity token with a 403
+pub async fn verify_csrf(State(exemptions): State<CsrfExemptions>, req: Request, next: Next) -> Response {
+    if req.method().is_safe() || exemptions.is_exempt(&req) {
+        return next.run(req).await;
+    }
+
+    let Some(token) = req.extensions().get::<CsrfToken>().cloned() else {
+        tracing::error!("CSRF token missing, is CsrfLayer enabled?");
+        return AppError::InternalServerError.into_response();
+    };
+    let (req, submitted) = match submitted_token(req).await {
+        Ok(submitted) => submitted,
+        Err(error) => return error.into_response(),
+    };
+
+    match submitted {
+        Some(submitted) if token.verify(&submitted).is_ok() => next.run(req).await,
+        Some(_) => {
+            tracing::warn!("Invalid CSRF token for {} {}", req.method(), req.uri().path());
+            AppError::Forbidden.into_response()
         }
+        None => {
+            tracing::warn!("Missing CSRF token for {} {}", req.method(), req.uri().path());
+            AppError::Forbidden.into_response()
+        }
+    }
+}
+
+/// The token sent in the header or, for forms, in the body, which is then put back in the request
+async fn submitted_token(req: Request) -> Result<(Request, Option<String>), AppError> {
+    if let Some(token) = req.headers().get(CSRF_HEADER).and_then(|value| value.to_str().ok()) {
+        let token = token.to_string();
+        return Ok((req, Some(token)));
     }
-}
\ No newline at end of file
+
+    let is_form = req.headers().get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("application/x-www-form-urlencoded"));
+    if !is_form {
+        return Ok((req, None));
+    }
+
+    let (parts, body) = req.into_parts();
+    let bytes = to_bytes(body, MAX_FORM_SIZE).await.or(Err(AppError::PayloadTooLarge))?;
+    let token = url::form_urlencoded::parse(&bytes)
+        .find(|(name, _)| name == CSRF_FORM_FIELD)
+        .map(|(_, value)| value.into_owned());
+
+    Ok((Request::from_parts(parts, Body::from(bytes)), token))
+}
+
+/// Set the CSRF cookie and return its authenticity token, to send back with state-changing requests
+pub async fn csrf_token(token: CsrfToken) -> Response {
+    match token.authenticity_token() {
+        Ok(authenticity_token) => (token, Json(json!({ CSRF_FORM_FIELD: authenticity_token }))).into_response(),
+        Err(_) => AppError::InternalServerError.into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{Router, middleware::from_fn_with_state, routing::{get, post}};
+    use axum::http::{StatusCode, header::{COOKIE, SET_COOKIE}};
+    use axum_csrf::{CsrfConfig, CsrfLayer};
+    use tower::ServiceExt;
+    use super::*;
+
+    fn app() -> Router {
+        Router::new()
+            .route("/csrf", get(csrf_token))
+            .route("/login", post(|body: String| async move { body }))
+            .route("/hooks/import", post(|| async { "imported" }))
+            .route("/api/tokens", post(|| async { "created" }))
+            .route("/admin/users/:id/lock", post(|| async { "locked" }))
+            .layer(from_fn_with_state(CsrfExemptions::new(vec!["/hooks/".to_string()]), verify_csrf))
+            .layer(CsrfLayer::new(CsrfConfig::default()))
+    }
+
+    /// The CSRF cookie and its authenticity token
+    async fn get_token(app: &Router) -> (String, String) {
+        let response = app.clone().oneshot(Request::get("/csrf").body(Body::empty()).unwrap()).await.unwrap();
+        let cookie = response.headers().get(SET_COOKIE).unwrap().to_str().unwrap()
+            .split(';').next().unwrap().to_string();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        (cookie, json[CSRF_FORM_FIELD].as_str().unwrap().to_string())
+    }
+
+    fn post_login(cookie: &str) -> axum::http::request::Builder {
+        Request::post("/login").header(COOKIE, cookie)
+    }
+
+    #[tokio::test]
+    async fn test_header_token() {
+        let app = app();
+        let (cookie, token) = get_token(&app).await;
+
+        let request = post_login(&cookie).header(CSRF_HEADER, &token).body(Body::from("{}")).unwrap();
+        assert_eq!(app.clone().oneshot(request).await.unwrap().status(), StatusCode::OK);
+
+        let request = post_login(&cookie).body(Body::from("{}")).unwrap();
+        assert_eq!(app.clone().oneshot(request).await.unwrap().status(), StatusCode::FORBIDDEN);
+
+        // the token of another cookie
+        let (other_cookie, _) = get_token(&app).await;
+        let request = post_login(&other_cookie).header(CSRF_HEADER, &token).body(Body::from("{}")).unwrap();
+        assert_eq!(app.clone().oneshot(request).await.unwrap().status(), StatusCode::FORBIDDEN);
+
+        let request = Request::post("/login").header(CSRF_HEADER, &token).body(Body::from("{}")).unwrap();
+        assert_eq!(app.oneshot(request).await.unwrap().status(), StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn test_form_token() {
+        let app = app();
+        let (cookie, token) = get_token(&app).await;
+        let form = url::form_urlencoded::Serializer::new(String::new())
+            .append_pair("email", "miles.davis@trumpet.com")
+            .append_pair(CSRF_FORM_FIELD, &token)
+            .finish();
+
+        let request = post_login(&cookie)
+            .header(CONTENT_TYPE, "application/x-www-form-urlencoded")
+            .body(Body::from(form.clone()))
+            .unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        // the handler still reads the whole form
+        assert_eq!(to_bytes(response.into_body(), usize::MAX).await.unwrap(), form.as_bytes());
+
+        let request = post_login(&cookie)
+            .header(CONTENT_TYPE, "application/x-www-form-urlencoded")
+            .body(Body::from("email=miles.davis%40trumpet.com&authenticity_token=forged"))
+            .unwrap();
+        assert_eq!(app.oneshot(request).await.unwrap().status(), StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn test_bearer_on_cookie_routes() {
+        let app = app();
+        let (cookie, _) = get_token(&app).await;
+
+        let request = Request::post("/admin/users/1/lock")
+            .header(COOKIE, &cookie)
+            .header(AUTHORIZATION, "Bearer bogus")
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(app.oneshot(request).await.unwrap().status(), StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn test_exemptions() {
+        let app = app();
+
+        let request = Request::post("/api/tokens").header(AUTHORIZATION, "Bearer anthere_pat_xxx").body(Body::empty()).unwrap();
+        assert_eq!(app.clone().oneshot(request).await.unwrap().status(), StatusCode::OK);
+
+        // only API routes accept bearer tokens, others are authenticated by the session cookie
+        let request = Request::post("/login").header(AUTHORIZATION, "Bearer anthere_pat_xxx").body(Body::empty()).unwrap();
+        assert_eq!(app.clone().oneshot(request).await.unwrap().status(), StatusCode::FORBIDDEN);
+
+        let request = Request::post("/hooks/import").body(Body::empty()).unwrap();
+        assert_eq!(app.clone().oneshot(request).await.unwrap().status(), StatusCode::OK);
+
+        let request = Request::post("/login").header(AUTHORIZATION, "Basic bWlsZXM6ZGF2aXM=").body(Body::empty()).unwrap();
+        assert_eq!(app.oneshot(request).await.unwrap().status(), StatusCode::FORBIDDEN);
+    }
+}
@@ -0,0 +1,90 @@
+//! Schema migrations, applied or reverted under a Postgres advisory lock, so that replicas starting
+//! together do not race to migrate
+
+use diesel::{sql_query, sql_types::BigInt, PgConnection, RunQueryDsl};
+use diesel::migration::{Migration, MigrationSource};
+use diesel::pg::Pg;
+use diesel_migrations::{embed_migrations, EmbeddedMigrations, MigrationHarness};
+
+pub const MIGRATIONS: EmbeddedMigrations = embed_migrations!("src/db/migrations/");
+
+/// Key of the advisory lock held while migrating, "anthere" in ASCII
+const MIGRATION_LOCK_KEY: i64 = 0x61_6e_74_68_65_72_65;
+
+pub type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+/// A migration, and whether it is applied
+#[derive(Debug, PartialEq)]
+pub struct MigrationStatus {
+    pub version: String,
+    pub applied: bool,
+}
+
+/// Run a task holding the migration lock, waiting for other instances to release it
+fn with_lock<T>(conn: &mut PgConnection, task: impl FnOnce(&mut PgConnection) -> Result<T>) -> Result<T> {
+    sql_query("SELECT pg_advisory_lock($1)").bind::<BigInt, _>(MIGRATION_LOCK_KEY).execute(conn)?;
+    let result = task(conn);
+    sql_query("SELECT pg_advisory_unlock($1)").bind::<BigInt, _>(MIGRATION_LOCK_KEY).execute(conn)?;
+    result
+}
+
+/// Apply the pending migrations, returning their versions. Migrations applied by another instance
+/// while waiting for the lock are not pending anymore once it is acquired.
+pub fn run_pending(conn: &mut PgConnection) -> Result<Vec<String>> {
+    with_lock(conn, |conn| {
+        let applied = conn.run_pending_migrations(MIGRATIONS)?;
+        Ok(applied.into_iter().map(|version| version.to_string()).collect())
+    })
+}
+
+/// Revert the last applied migration, returning its version
+pub fn revert_last(conn: &mut PgConnection) -> Result<String> {
+    with_lock(conn, |conn| Ok(conn.revert_last_migration(MIGRATIONS)?.to_string()))
+}
+
+/// The versions of the migrations not applied yet
+pub fn pending(conn: &mut PgConnection) -> Result<Vec<String>> {
+    Ok(status(conn)?.into_iter().filter(|migration| !migration.applied).map(|migration| migration.version).collect())
+}
+
+/// Every migration, in order
+pub fn status(conn: &mut PgConnection) -> Result<Vec<MigrationStatus>> {
+    let applied = conn.applied_migrations()?;
+    let migrations = MigrationSource::<Pg>::migrations(&MIGRATIONS)?;
+
+    Ok(migrations.iter()
+        .map(|migration| migration.name().version())
+        .map(|version| MigrationStatus { applied: applied.contains(&version), version: version.to_string() })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::FutureExt;
+    use crate::Config;
+    use crate::db::TestDb;
+    use super::*;
+
+    fn get_db_pool() -> TestDb {
+        let config = Config::new().unwrap();
+        TestDb::new(&config)
+    }
+
+    #[tokio::test]
+    async fn test_revert_and_apply() {
+        let db = get_db_pool();
+        db.run_test(|pool| async move {
+            let conn = &mut pool.get().unwrap();
+            let migrations = status(conn).unwrap();
+            assert!(migrations.iter().all(|migration| migration.applied));
+            assert!(pending(conn).unwrap().is_empty());
+
+            let last = migrations.last().unwrap().version.clone();
+            assert_eq!(revert_last(conn).unwrap(), last);
+            assert_eq!(pending(conn).unwrap(), vec![last.clone()]);
+
+            assert_eq!(run_pending(conn).unwrap(), vec![last]);
+            assert!(pending(conn).unwrap().is_empty());
+        }.boxed()).await;
+    }
+}
@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE users
+    DROP COLUMN role,
+    DROP COLUMN locked_at;
\ No newline at end of file
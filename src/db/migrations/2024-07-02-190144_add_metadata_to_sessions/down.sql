@@ -0,0 +1,7 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE sessions
+    DROP COLUMN user_id,
+    DROP COLUMN created_at,
+    DROP COLUMN last_seen_at,
+    DROP COLUMN ip,
+    DROP COLUMN user_agent;
\ No newline at end of file
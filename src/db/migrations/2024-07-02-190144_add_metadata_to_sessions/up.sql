@@ -0,0 +1,8 @@
+ALTER TABLE sessions
+    ADD COLUMN user_id INT REFERENCES users (id) ON DELETE CASCADE,
+    ADD COLUMN created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    ADD COLUMN last_seen_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    ADD COLUMN ip INET,
+    ADD COLUMN user_agent VARCHAR;
+
+CREATE INDEX IF NOT EXISTS sessions_user_id_idx ON sessions (user_id);
\ No newline at end of file
@@ -0,0 +1,16 @@
+CREATE TABLE IF NOT EXISTS remember_tokens
+(
+    id SERIAL PRIMARY KEY,
+    user_id INT NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    series VARCHAR NOT NULL UNIQUE,
+    token_hash BYTEA NOT NULL,
+    previous_token_hash BYTEA,
+    rotated_at TIMESTAMP NOT NULL DEFAULT NOW(),
+    expires_at TIMESTAMP NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT NOW(),
+    updated_at TIMESTAMP NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX IF NOT EXISTS remember_tokens_user_id_idx ON remember_tokens (user_id);
+
+SELECT diesel_manage_updated_at('remember_tokens');
@@ -0,0 +1,18 @@
+-- Account deletions, which are kept once the user is erased as an audit record
+CREATE TABLE IF NOT EXISTS account_deletions
+(
+    id SERIAL PRIMARY KEY,
+    user_id INT NOT NULL,
+    requested_at TIMESTAMP NOT NULL DEFAULT NOW(),
+    erase_after TIMESTAMP NOT NULL,
+    canceled_at TIMESTAMP,
+    erased_at TIMESTAMP,
+    created_at TIMESTAMP NOT NULL DEFAULT NOW(),
+    updated_at TIMESTAMP NOT NULL DEFAULT NOW()
+);
+
+-- a user has at most one pending deletion
+CREATE UNIQUE INDEX IF NOT EXISTS account_deletions_pending_idx ON account_deletions (user_id)
+    WHERE canceled_at IS NULL AND erased_at IS NULL;
+
+SELECT diesel_manage_updated_at('account_deletions');
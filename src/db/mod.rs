@@ -1,4 +1,81 @@
+use std::fmt;
+use diesel::PgConnection;
+use diesel::r2d2::{ConnectionManager, Pool};
+use tokio::task::JoinError;
+
 mod test_db;
 pub mod seeds;
+pub mod migrate;
+
+pub use test_db::TestDb;
+
+/// Failure to run a blocking database task, before the task itself could return an error
+#[derive(Debug)]
+pub enum BlockingError {
+    Pool(r2d2::Error),
+    Join(JoinError),
+}
+
+impl std::error::Error for BlockingError {}
+
+impl fmt::Display for BlockingError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BlockingError::Pool(e) => write!(f, "Could not get a database connection: {}", e),
+            BlockingError::Join(e) => write!(f, "Database task failed: {}", e),
+        }
+    }
+}
+
+/// Run Diesel queries on the blocking thread pool, so that neither waiting for a pooled connection
+/// nor the queries themselves stall the async runtime. They run in the caller's span.
+pub async fn run_blocking<T, E, F>(db: &Pool<ConnectionManager<PgConnection>>, task: F) -> Result<T, E>
+where
+    F: FnOnce(&mut PgConnection) -> Result<T, E> + Send + 'static,
+    T: Send + 'static,
+    E: From<BlockingError> + Send + 'static,
+{
+    let db = db.clone();
+    crate::telemetry::spawn_blocking(move || {
+        let conn = &mut db.get().map_err(|e| E::from(BlockingError::Pool(e)))?;
+        task(conn)
+    }).await
+        .map_err(|e| E::from(BlockingError::Join(e)))?
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+    use diesel::{sql_query, RunQueryDsl};
+    use futures::FutureExt;
+    use crate::Config;
+    use crate::errors::{adapt_app_error, AppError};
+    use super::*;
+
+    fn get_db_pool() -> TestDb {
+        let config = Config::new().unwrap();
+        TestDb::new(&config)
+    }
+
+    // `tokio::test` runs on a single thread: a blocking query would stall the timer below
+    #[tokio::test]
+    async fn test_queries_do_not_block_the_runtime() {
+        let db = get_db_pool();
+        db.run_test(|pool| async move {
+            let queries = (0..4).map(|_| run_blocking(&pool, |conn| {
+                sql_query("SELECT pg_sleep(0.5)").execute(conn).map_err(adapt_app_error)
+            }));
+            let queries = futures::future::join_all(queries);
+
+            let timer = async {
+                let start = Instant::now();
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                start.elapsed()
+            };
 
-pub use test_db::TestDb;
\ No newline at end of file
+            let (results, elapsed) = tokio::join!(queries, timer);
+            assert!(results.into_iter().all(|result: Result<usize, AppError>| result.is_ok()));
+            assert!(elapsed < Duration::from_millis(400), "runtime stalled for {:?}", elapsed);
+        }.boxed()).await;
+    }
+}
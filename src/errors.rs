@@ -1,11 +1,27 @@
 use std::fmt;
-use serde::de::StdError;
+use std::time::Duration;
+use axum::http::{StatusCode, header::{CONTENT_TYPE, RETRY_AFTER}};
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use diesel::result::DatabaseErrorKind;
+use serde::{de::StdError, Serialize};
 use tokio::task::JoinError;
 
-#[derive(Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum AppError {
     InternalServerError,
     NotFound,
+    /// Invalid input, with what is wrong with it
+    Validation(String),
+    Unauthorized,
+    Forbidden,
+    /// Conflicts with the current state, e.g. a duplicate
+    Conflict(String),
+    /// Too many requests, with when to retry if known
+    RateLimited(Option<Duration>),
+    PayloadTooLarge,
+    /// Content in a format we cannot read
+    UnsupportedFormat(String),
 }
 
 impl std::error::Error for AppError {}
@@ -15,12 +31,76 @@ pub fn adapt_app_error<T: Error>(error: T) -> AppError {
     error.as_app_error()
 }
 
+impl AppError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            AppError::InternalServerError => StatusCode::INTERNAL_SERVER_ERROR,
+            AppError::NotFound => StatusCode::NOT_FOUND,
+            AppError::Validation(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            AppError::Unauthorized => StatusCode::UNAUTHORIZED,
+            AppError::Forbidden => StatusCode::FORBIDDEN,
+            AppError::Conflict(_) => StatusCode::CONFLICT,
+            AppError::RateLimited(_) => StatusCode::TOO_MANY_REQUESTS,
+            AppError::PayloadTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            AppError::UnsupportedFormat(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+        }
+    }
+
+    fn detail(&self) -> Option<&str> {
+        match self {
+            AppError::Validation(detail) | AppError::Conflict(detail) | AppError::UnsupportedFormat(detail) => Some(detail),
+            _ => None,
+        }
+    }
+}
+
 impl fmt::Display for AppError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             AppError::NotFound => write!(f, "Not found"),
             AppError::InternalServerError => write!(f, "Internal server error"),
+            AppError::Validation(detail) => write!(f, "Invalid input: {}", detail),
+            AppError::Unauthorized => write!(f, "Unauthorized"),
+            AppError::Forbidden => write!(f, "Forbidden"),
+            AppError::Conflict(detail) => write!(f, "Conflict: {}", detail),
+            AppError::RateLimited(_) => write!(f, "Too many requests"),
+            AppError::PayloadTooLarge => write!(f, "Payload too large"),
+            AppError::UnsupportedFormat(detail) => write!(f, "Unsupported format: {}", detail),
+        }
+    }
+}
+
+/// An RFC 7807 problem details object
+#[derive(Serialize)]
+struct Problem<'a> {
+    #[serde(rename = "type")]
+    problem_type: &'static str,
+    title: &'static str,
+    status: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    detail: Option<&'a str>,
+    /// Identifies the request in the logs
+    #[serde(skip_serializing_if = "Option::is_none")]
+    request_id: Option<String>,
+}
+
+impl IntoResponse for AppError {
+    fn into_response(self) -> Response {
+        let status = self.status();
+        let problem = Problem {
+            // the status is enough to tell problems apart
+            problem_type: "about:blank",
+            title: status.canonical_reason().unwrap_or("Error"),
+            status: status.as_u16(),
+            detail: self.detail(),
+            request_id: crate::request_id::current(),
+        };
+
+        let mut response = (status, [(CONTENT_TYPE, "application/problem+json")], Json(problem)).into_response();
+        if let AppError::RateLimited(Some(retry_after)) = self {
+            response.headers_mut().insert(RETRY_AFTER, retry_after.as_secs().max(1).into());
         }
+        response
     }
 }
 
@@ -30,11 +110,26 @@ pub trait Error {
 
 impl Error for diesel::result::Error {
     fn as_app_error(&self) -> AppError {
-        tracing::error!("{}", self);
-        match self {
-            diesel::result::Error::NotFound => AppError::NotFound,
+        use diesel::result::Error::{DatabaseError, NotFound};
+
+        let error = match self {
+            NotFound => AppError::NotFound,
+            DatabaseError(DatabaseErrorKind::UniqueViolation, _) => AppError::Conflict(String::from("already exists")),
+            // deleting a row still referenced, or referencing a missing one
+            DatabaseError(DatabaseErrorKind::ForeignKeyViolation, info)
+                if info.details().is_some_and(|details| details.contains("still referenced")) =>
+                AppError::Conflict(String::from("still in use")),
+            DatabaseError(DatabaseErrorKind::ForeignKeyViolation, _) => AppError::Validation(String::from("refers to a missing resource")),
+            DatabaseError(DatabaseErrorKind::NotNullViolation | DatabaseErrorKind::CheckViolation, _) =>
+                AppError::Validation(String::from("missing or invalid value")),
             _ => AppError::InternalServerError,
+        };
+        if error == AppError::InternalServerError {
+            tracing::error!("{}", self);
+        } else {
+            tracing::debug!("{}", self);
         }
+        error
     }
 }
 
@@ -54,6 +149,69 @@ impl Error for JoinError {
     }
 }
 
+impl Error for crate::oidc::OidcError {
+    fn as_app_error(&self) -> AppError {
+        tracing::error!("{}", self);
+        AppError::InternalServerError
+    }
+}
+
+impl Error for redis::RedisError {
+    fn as_app_error(&self) -> AppError {
+        tracing::error!("{}", self);
+        AppError::InternalServerError
+    }
+}
+
+impl Error for crate::db::BlockingError {
+    fn as_app_error(&self) -> AppError {
+        tracing::error!("{}", self);
+        AppError::InternalServerError
+    }
+}
+
+impl From<crate::db::BlockingError> for AppError {
+    fn from(error: crate::db::BlockingError) -> Self {
+        adapt_app_error(error)
+    }
+}
+
+impl Error for crate::config::ConfigError {
+    fn as_app_error(&self) -> AppError {
+        tracing::error!("{}", self);
+        AppError::InternalServerError
+    }
+}
+
+impl Error for tower_sessions::session::Error {
+    fn as_app_error(&self) -> AppError {
+        tracing::error!("{}", self);
+        AppError::InternalServerError
+    }
+}
+
+impl Error for tower_sessions::session_store::Error {
+    fn as_app_error(&self) -> AppError {
+        tracing::error!("{}", self);
+        AppError::InternalServerError
+    }
+}
+
+impl Error for axum_login::Error<crate::auth::Backend> {
+    fn as_app_error(&self) -> AppError {
+        match self {
+            axum_login::Error::Backend(error) => error.as_app_error(),
+            axum_login::Error::Session(error) => error.as_app_error(),
+        }
+    }
+}
+
+impl Error for AppError {
+    fn as_app_error(&self) -> AppError {
+        self.clone()
+    }
+}
+
 impl Error for &str {
     fn as_app_error(&self) -> AppError {
         tracing::error!("{}", self);
@@ -66,4 +224,57 @@ impl Error for Box<dyn StdError + Send + Sync> {
         tracing::error!("{}", self);
         AppError::InternalServerError
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use axum::body::to_bytes;
+    use futures::FutureExt;
+    use crate::{Config, db::TestDb};
+    use super::*;
+
+    #[tokio::test]
+    async fn test_problem_response() {
+        let response = AppError::Conflict(String::from("already exists")).into_response();
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        assert_eq!(response.headers()[CONTENT_TYPE], "application/problem+json");
+
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let problem: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(problem, serde_json::json!({
+            "type": "about:blank",
+            "title": "Conflict",
+            "status": 409,
+            "detail": "already exists",
+        }));
+
+        let response = AppError::RateLimited(Some(Duration::from_secs(30))).into_response();
+        assert_eq!(response.headers()[RETRY_AFTER], "30");
+    }
+
+    #[tokio::test]
+    async fn test_constraint_violations() {
+        let db = TestDb::new(&Config::new().unwrap());
+        db.run_test(|pool| async move {
+            use crate::schema::{api_tokens, users};
+            use diesel::prelude::*;
+
+            let conn = &mut pool.get().unwrap();
+            let duplicate = diesel::insert_into(users::table)
+                .values((users::email.eq("miles.davis@trumpet.com"), users::password.eq("")))
+                .execute(conn)
+                .unwrap_err();
+            assert!(matches!(adapt_app_error(duplicate), AppError::Conflict(_)));
+
+            let missing_user = diesel::insert_into(api_tokens::table)
+                .values((
+                    api_tokens::user_id.eq(-1),
+                    api_tokens::name.eq("orphan"),
+                    api_tokens::token_hash.eq(vec![0u8]),
+                    api_tokens::scopes.eq(Vec::<Option<String>>::new()),
+                ))
+                .execute(conn)
+                .unwrap_err();
+            assert!(matches!(adapt_app_error(missing_user), AppError::Validation(_)));
+        }.boxed()).await;
+    }
+}
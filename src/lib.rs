@@ -1,5 +1,5 @@
 use diesel::PgConnection;
-use diesel::r2d2::{ConnectionManager, Pool};
+use diesel::r2d2::{ConnectionManager, CustomizeConnection, Pool};
 
 mod db;
 
@@ -8,24 +8,98 @@ pub mod schema;
 
 pub mod errors;
 
+mod request_id;
+
+pub mod telemetry;
+
+mod prometheus;
+
 mod config;
-pub use config::Config;
+pub use config::{Config, ConfigError, LogConfig, LogFormat, PoolConfig, SessionStoreConfig};
 
 mod app;
 pub use app::App;
 
+pub mod cli;
+
 mod store;
 
+mod redis_store;
+
+mod caching_store;
+
+pub mod session_key;
+
 mod auth;
 
+mod webauthn;
+
+mod api_token;
+
+mod remember_me;
+
+mod account_deletion;
+
+mod oidc;
+
 pub mod csrf;
 
 pub fn get_connection_pool(database_url: &str) -> Pool<ConnectionManager<PgConnection>> {
+    build_connection_pool(database_url, &PoolConfig::default())
+}
+
+pub fn build_connection_pool(database_url: &str, config: &PoolConfig) -> Pool<ConnectionManager<PgConnection>> {
     let manager = ConnectionManager::<PgConnection>::new(database_url);
     // Refer to the `r2d2` documentation for more methods to use
     // when building a connection pool
     Pool::builder()
         .test_on_check_out(true)
+        .max_size(config.max_size)
+        .min_idle(config.min_idle)
+        .connection_timeout(config.connection_timeout)
+        .idle_timeout(config.idle_timeout)
+        .max_lifetime(config.max_lifetime)
+        .connection_customizer(Box::new(ConnectionOptions { statement_timeout: config.statement_timeout }))
         .build(manager)
         .expect("Could not build connection pool")
 }
+
+/// Session settings applied to every new connection
+#[derive(Debug)]
+struct ConnectionOptions {
+    statement_timeout: Option<std::time::Duration>,
+}
+
+impl CustomizeConnection<PgConnection, diesel::r2d2::Error> for ConnectionOptions {
+    fn on_acquire(&self, conn: &mut PgConnection) -> Result<(), diesel::r2d2::Error> {
+        use diesel::RunQueryDsl;
+
+        if let Some(timeout) = self.statement_timeout {
+            diesel::sql_query(format!("SET statement_timeout = {}", timeout.as_millis()))
+                .execute(conn)
+                .map_err(diesel::r2d2::Error::QueryError)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use diesel::RunQueryDsl;
+    use super::*;
+
+    #[test]
+    fn test_statement_timeout() {
+        let config = Config::new().unwrap();
+        let pool = build_connection_pool(&config.database_url, &PoolConfig {
+            max_size: 1,
+            statement_timeout: Some(Duration::from_millis(100)),
+            ..PoolConfig::default()
+        });
+
+        let conn = &mut pool.get().unwrap();
+        assert!(diesel::sql_query("SELECT pg_sleep(0.01)").execute(conn).is_ok());
+        assert!(diesel::sql_query("SELECT pg_sleep(1)").execute(conn).is_err());
+    }
+}
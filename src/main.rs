@@ -1,9 +1,52 @@
-use anthere::App;
+use clap::Parser;
+use anthere::{App, Config, telemetry};
+use anthere::cli::{self, Cli, Command};
+use anthere::session_key::generate_key;
 
 #[tokio::main]
 async fn main() {
-    tracing_subscriber::fmt::init();
+    let cli = Cli::parse();
+    if let Some(Command::GenerateKey) = cli.command {
+        println!("{}", generate_key());
+        return;
+    }
 
-    let server = App::new().await.expect("Unable to create server");
-    server.serve().await.expect("Unable to serve app");
+    // logging is configured by the configuration, which logs to stderr while it loads
+    let loading = tracing_subscriber::fmt().with_writer(std::io::stderr).finish();
+    let config = tracing::subscriber::with_default(loading, Config::new);
+    let config = match config {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    if cli.print_config {
+        println!("{}", config.redacted());
+        return;
+    }
+
+    match cli.command {
+        None | Some(Command::Serve) => {
+            telemetry::init(&config.log);
+
+            // e.g. pending migrations, which are not applied automatically
+            let server = match App::new(config).await {
+                Ok(server) => server,
+                Err(e) => {
+                    tracing::error!("Unable to create server: {}", e);
+                    std::process::exit(1);
+                }
+            };
+            server.serve().await.expect("Unable to serve app");
+        }
+        // the output of admin commands is kept apart from their logs
+        Some(command) => {
+            tracing_subscriber::fmt().with_writer(std::io::stderr).with_max_level(tracing::Level::WARN).init();
+            if let Err(e) = cli::run(command, &config).await {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        }
+    }
 }
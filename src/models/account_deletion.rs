@@ -0,0 +1,24 @@
+use diesel::prelude::*;
+
+/// A deletion requested by a user, erasing their account once its grace period is over unless
+/// they log in before. It is kept afterwards as an audit record.
+#[derive(Queryable, Selectable, Clone, Debug)]
+#[diesel(table_name = crate::schema::account_deletions)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct AccountDeletion {
+    pub id: i32,
+    pub user_id: i32,
+    pub requested_at: chrono::NaiveDateTime,
+    pub erase_after: chrono::NaiveDateTime,
+    pub canceled_at: Option<chrono::NaiveDateTime>,
+    pub erased_at: Option<chrono::NaiveDateTime>,
+    pub created_at: chrono::NaiveDateTime,
+    pub updated_at: chrono::NaiveDateTime
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = crate::schema::account_deletions)]
+pub struct NewAccountDeletion {
+    pub user_id: i32,
+    pub erase_after: chrono::NaiveDateTime,
+}
@@ -0,0 +1,52 @@
+use diesel::prelude::*;
+use crate::api_token::Scope;
+
+#[derive(Queryable, Selectable, Clone)]
+#[diesel(table_name = crate::schema::api_tokens)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct ApiToken {
+    pub id: i32,
+    pub user_id: i32,
+    pub name: String,
+    pub token_hash: Vec<u8>,
+    pub scopes: Vec<Option<String>>,
+    pub expires_at: Option<chrono::NaiveDateTime>,
+    pub last_used_at: Option<chrono::NaiveDateTime>,
+    pub created_at: chrono::NaiveDateTime,
+    pub updated_at: chrono::NaiveDateTime
+}
+
+impl ApiToken {
+    pub fn scopes(&self) -> Vec<Scope> {
+        self.scopes.iter()
+            .flatten()
+            .filter_map(|scope| scope.parse().ok())
+            .collect()
+    }
+}
+
+impl std::fmt::Debug for ApiToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ApiToken")
+            .field("id", &self.id)
+            .field("user_id", &self.user_id)
+            .field("name", &self.name)
+            .field("token_hash", &"[redacted]")
+            .field("scopes", &self.scopes)
+            .field("expires_at", &self.expires_at)
+            .field("last_used_at", &self.last_used_at)
+            .field("created_at", &self.created_at)
+            .field("updated_at", &self.updated_at)
+            .finish()
+    }
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = crate::schema::api_tokens)]
+pub struct NewApiToken<'a> {
+    pub user_id: i32,
+    pub name: &'a str,
+    pub token_hash: Vec<u8>,
+    pub scopes: Vec<Option<String>>,
+    pub expires_at: Option<chrono::NaiveDateTime>,
+}
\ No newline at end of file
@@ -1,5 +1,20 @@
 mod user;
-pub use user::{User, NewUser};
+pub use user::{User, NewUser, Role};
 
 mod session;
-pub use session::Session;
\ No newline at end of file
+pub use session::{Session, NewSession};
+
+mod webauthn_credential;
+pub use webauthn_credential::{WebauthnCredential, NewWebauthnCredential};
+
+mod api_token;
+pub use api_token::{ApiToken, NewApiToken};
+
+mod oidc_identity;
+pub use oidc_identity::{OidcIdentity, NewOidcIdentity};
+
+mod remember_token;
+pub use remember_token::{RememberToken, NewRememberToken};
+
+mod account_deletion;
+pub use account_deletion::{AccountDeletion, NewAccountDeletion};
@@ -0,0 +1,22 @@
+use diesel::prelude::*;
+
+/// Links a user to the subject identifier of an OpenID Connect provider
+#[derive(Queryable, Selectable, Clone, Debug)]
+#[diesel(table_name = crate::schema::oidc_identities)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct OidcIdentity {
+    pub id: i32,
+    pub user_id: i32,
+    pub issuer: String,
+    pub subject: String,
+    pub created_at: chrono::NaiveDateTime,
+    pub updated_at: chrono::NaiveDateTime
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = crate::schema::oidc_identities)]
+pub struct NewOidcIdentity<'a> {
+    pub user_id: i32,
+    pub issuer: &'a str,
+    pub subject: &'a str,
+}
\ No newline at end of file
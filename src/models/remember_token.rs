@@ -0,0 +1,41 @@
+use diesel::prelude::*;
+
+/// A persistent login series, whose token is replaced every time it is used
+#[derive(Queryable, Selectable, Clone)]
+#[diesel(table_name = crate::schema::remember_tokens)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct RememberToken {
+    pub id: i32,
+    pub user_id: i32,
+    pub series: String,
+    pub token_hash: Vec<u8>,
+    pub previous_token_hash: Option<Vec<u8>>,
+    pub rotated_at: chrono::NaiveDateTime,
+    pub expires_at: chrono::NaiveDateTime,
+    pub created_at: chrono::NaiveDateTime,
+    pub updated_at: chrono::NaiveDateTime
+}
+
+impl std::fmt::Debug for RememberToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RememberToken")
+            .field("id", &self.id)
+            .field("user_id", &self.user_id)
+            .field("series", &"[redacted]")
+            .field("token_hash", &"[redacted]")
+            .field("rotated_at", &self.rotated_at)
+            .field("expires_at", &self.expires_at)
+            .field("created_at", &self.created_at)
+            .field("updated_at", &self.updated_at)
+            .finish()
+    }
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = crate::schema::remember_tokens)]
+pub struct NewRememberToken<'a> {
+    pub user_id: i32,
+    pub series: &'a str,
+    pub token_hash: Vec<u8>,
+    pub expires_at: chrono::NaiveDateTime,
+}
@@ -1,10 +1,37 @@
 use diesel::prelude::*;
 
-#[derive(Queryable, Selectable, Clone, Insertable)]
+#[derive(Queryable, Selectable, Clone)]
 #[diesel(table_name = crate::schema::sessions)]
 #[diesel(check_for_backend(diesel::pg::Pg))]
 pub struct Session {
     pub id: String,
     pub data: Vec<u8>,
     pub expiry_date: time::OffsetDateTime,
-}
\ No newline at end of file
+    pub user_id: Option<i32>,
+    pub created_at: time::OffsetDateTime,
+    pub last_seen_at: time::OffsetDateTime,
+    pub ip: Option<ipnet::IpNet>,
+    pub user_agent: Option<String>,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = crate::schema::sessions)]
+pub struct NewSession {
+    pub id: String,
+    pub data: Vec<u8>,
+    pub expiry_date: time::OffsetDateTime,
+    pub user_id: Option<i32>,
+    pub ip: Option<ipnet::IpNet>,
+    pub user_agent: Option<String>,
+}
+
+impl Session {
+    /// Opaque identifier exposed to clients, so the session id itself never leaves the cookie
+    pub fn handle(&self) -> String {
+        use base64::Engine;
+        use sha2::{Digest, Sha256};
+
+        let digest = Sha256::digest(self.id.as_bytes());
+        base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(&digest[..16])
+    }
+}
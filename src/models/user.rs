@@ -1,5 +1,35 @@
+use std::{fmt, str::FromStr};
 use diesel::prelude::*;
 use axum_login::AuthUser;
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    User,
+    Admin,
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Role::User => write!(f, "user"),
+            Role::Admin => write!(f, "admin"),
+        }
+    }
+}
+
+impl FromStr for Role {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "user" => Ok(Role::User),
+            "admin" => Ok(Role::Admin),
+            _ => Err("Unknown role"),
+        }
+    }
+}
 
 #[derive(Queryable, Selectable, Clone)]
 #[diesel(table_name = crate::schema::users)]
@@ -19,7 +49,20 @@ pub struct User {
     pub confirmation_sent_at: Option<chrono::NaiveDateTime>,
     pub confirmed_at: Option<chrono::NaiveDateTime>,
     pub created_at: chrono::NaiveDateTime,
-    pub updated_at: chrono::NaiveDateTime
+    pub updated_at: chrono::NaiveDateTime,
+    pub role: String,
+    pub locked_at: Option<chrono::NaiveDateTime>
+}
+
+impl User {
+    /// Unknown roles (which the database constraint prevents) get the least privileges
+    pub fn role(&self) -> Role {
+        self.role.parse().unwrap_or(Role::User)
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.locked_at.is_some()
+    }
 }
 
 impl std::fmt::Debug for User {
@@ -40,6 +83,8 @@ impl std::fmt::Debug for User {
             .field("confirmed_at", &self.confirmed_at)
             .field("created_at", &self.created_at)
             .field("updated_at", &self.updated_at)
+            .field("role", &self.role)
+            .field("locked_at", &self.locked_at)
             .finish()
     }
 }
@@ -0,0 +1,26 @@
+use diesel::prelude::*;
+
+#[derive(Queryable, Selectable, Clone, Debug)]
+#[diesel(table_name = crate::schema::webauthn_credentials)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct WebauthnCredential {
+    pub id: i32,
+    pub user_id: i32,
+    pub credential_id: Vec<u8>,
+    pub public_key: Vec<u8>,
+    pub sign_count: i64,
+    pub name: String,
+    pub last_used_at: Option<chrono::NaiveDateTime>,
+    pub created_at: chrono::NaiveDateTime,
+    pub updated_at: chrono::NaiveDateTime
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = crate::schema::webauthn_credentials)]
+pub struct NewWebauthnCredential<'a> {
+    pub user_id: i32,
+    pub credential_id: Vec<u8>,
+    pub public_key: Vec<u8>,
+    pub sign_count: i64,
+    pub name: &'a str,
+}
@@ -0,0 +1,114 @@
+//! A minimal OpenID Connect provider serving discovery, keys and signed ID tokens, used to test the flow.
+
+use chrono::{Duration, Utc};
+use openidconnect::{
+    AccessToken, Audience, AuthUrl, EmptyAdditionalClaims, EmptyAdditionalProviderMetadata,
+    EmptyExtraTokenFields, EndUserEmail, IssuerUrl, JsonWebKeyId, JsonWebKeySetUrl, Nonce,
+    PrivateSigningKey, ResponseTypes, StandardClaims, SubjectIdentifier, TokenUrl,
+};
+use openidconnect::core::{
+    CoreIdToken, CoreIdTokenClaims, CoreIdTokenFields, CoreJsonWebKeySet, CoreJwsSigningAlgorithm,
+    CoreProviderMetadata, CoreResponseType, CoreRsaPrivateSigningKey, CoreSubjectIdentifierType,
+    CoreTokenResponse, CoreTokenType,
+};
+use wiremock::{Mock, MockServer, ResponseTemplate};
+use wiremock::matchers::{body_string_contains, method, path};
+use crate::config::OidcConfig;
+
+const CLIENT_ID: &str = "anthere";
+const CODE: &str = "mock-authorization-code";
+
+pub struct MockProvider {
+    server: MockServer,
+}
+
+impl MockProvider {
+    pub async fn start() -> Self {
+        let server = MockServer::start().await;
+        let provider = Self { server };
+
+        let issuer = provider.issuer();
+        let metadata = CoreProviderMetadata::new(
+            IssuerUrl::new(issuer.clone()).unwrap(),
+            AuthUrl::new(format!("{}/authorize", issuer)).unwrap(),
+            JsonWebKeySetUrl::new(format!("{}/jwks", issuer)).unwrap(),
+            vec![ResponseTypes::new(vec![CoreResponseType::Code])],
+            vec![CoreSubjectIdentifierType::Public],
+            vec![CoreJwsSigningAlgorithm::RsaSsaPkcs1V15Sha256],
+            EmptyAdditionalProviderMetadata {},
+        ).set_token_endpoint(Some(TokenUrl::new(format!("{}/token", issuer)).unwrap()));
+
+        Mock::given(method("GET"))
+            .and(path("/.well-known/openid-configuration"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(metadata))
+            .mount(&provider.server).await;
+
+        let jwks = CoreJsonWebKeySet::new(vec![signing_key().as_verification_key()]);
+        Mock::given(method("GET"))
+            .and(path("/jwks"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(jwks))
+            .mount(&provider.server).await;
+
+        provider
+    }
+
+    pub fn issuer(&self) -> String {
+        self.server.uri()
+    }
+
+    pub fn code(&self) -> String {
+        CODE.to_string()
+    }
+
+    pub fn config(&self) -> OidcConfig {
+        OidcConfig {
+            issuer_url: self.issuer(),
+            client_id: CLIENT_ID.to_string(),
+            client_secret: Some("secret".to_string()),
+            redirect_url: "http://localhost:7878/login/oidc/callback".to_string(),
+        }
+    }
+
+    /// Answer the next code exchange with an ID token for the given user
+    pub async fn issue(&self, subject: &str, email: Option<&str>, email_verified: bool, nonce: &Nonce) {
+        let access_token = AccessToken::new("mock-access-token".to_string());
+        let claims = CoreIdTokenClaims::new(
+            IssuerUrl::new(self.issuer()).unwrap(),
+            vec![Audience::new(CLIENT_ID.to_string())],
+            Utc::now() + Duration::seconds(300),
+            Utc::now(),
+            StandardClaims::new(SubjectIdentifier::new(subject.to_string()))
+                .set_email(email.map(|email| EndUserEmail::new(email.to_string())))
+                .set_email_verified(Some(email_verified)),
+            EmptyAdditionalClaims {},
+        ).set_nonce(Some(nonce.clone()));
+        let id_token = CoreIdToken::new(
+            claims,
+            &signing_key(),
+            CoreJwsSigningAlgorithm::RsaSsaPkcs1V15Sha256,
+            Some(&access_token),
+            None,
+        ).unwrap();
+        let response = CoreTokenResponse::new(
+            access_token,
+            CoreTokenType::Bearer,
+            CoreIdTokenFields::new(Some(id_token), EmptyExtraTokenFields {}),
+        );
+
+        // the code exchange must carry the PKCE verifier, and the code can only be used once
+        Mock::given(method("POST"))
+            .and(path("/token"))
+            .and(body_string_contains(format!("code={}", CODE)))
+            .and(body_string_contains("code_verifier="))
+            .respond_with(ResponseTemplate::new(200).set_body_json(response))
+            .up_to_n_times(1)
+            .mount(&self.server).await;
+    }
+}
+
+fn signing_key() -> CoreRsaPrivateSigningKey {
+    CoreRsaPrivateSigningKey::from_pem(
+        include_str!("mock_provider_key.pem"),
+        Some(JsonWebKeyId::new("mock".to_string())),
+    ).unwrap()
+}
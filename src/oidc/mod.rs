@@ -0,0 +1,181 @@
+//! OpenID Connect single sign-on, using the authorization code flow with PKCE.
+
+use std::fmt;
+use openidconnect::{
+    AccessTokenHash, AuthorizationCode, ClientId, ClientSecret, CsrfToken, IssuerUrl, Nonce,
+    OAuth2TokenResponse, PkceCodeChallenge, PkceCodeVerifier, RedirectUrl, Scope, TokenResponse,
+};
+use openidconnect::core::{CoreAuthenticationFlow, CoreClient, CoreProviderMetadata};
+use openidconnect::reqwest::async_http_client;
+use serde::{Deserialize, Serialize};
+use url::Url;
+use crate::config::OidcConfig;
+
+#[cfg(test)]
+pub mod mock_provider;
+
+#[derive(Debug, PartialEq)]
+pub enum OidcError {
+    Discovery(String),
+    StateMismatch,
+    TokenExchange(String),
+    MissingIdToken,
+    InvalidIdToken(String),
+    AccessTokenMismatch,
+}
+
+impl std::error::Error for OidcError {}
+
+impl fmt::Display for OidcError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            OidcError::Discovery(e) => write!(f, "OpenID Connect discovery failed: {}", e),
+            OidcError::StateMismatch => write!(f, "OpenID Connect state does not match"),
+            OidcError::TokenExchange(e) => write!(f, "OpenID Connect code exchange failed: {}", e),
+            OidcError::MissingIdToken => write!(f, "OpenID Connect provider did not return an ID token"),
+            OidcError::InvalidIdToken(e) => write!(f, "Invalid ID token: {}", e),
+            OidcError::AccessTokenMismatch => write!(f, "Access token hash does not match the ID token"),
+        }
+    }
+}
+
+/// State of a pending authorization, kept in the session until the provider redirects back
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct OidcFlow {
+    csrf_state: CsrfToken,
+    nonce: Nonce,
+    pkce_verifier: String,
+}
+
+/// The identity asserted by a validated ID token
+#[derive(Clone, Debug, PartialEq)]
+pub struct VerifiedIdentity {
+    pub issuer: String,
+    pub subject: String,
+    pub email: Option<String>,
+    pub email_verified: bool,
+}
+
+#[derive(Clone, Debug)]
+pub struct Oidc {
+    client: CoreClient,
+    issuer: String,
+}
+
+impl Oidc {
+    /// Fetch the provider metadata and signing keys through OpenID Connect discovery
+    pub async fn discover(config: &OidcConfig) -> Result<Self, OidcError> {
+        let issuer_url = IssuerUrl::new(config.issuer_url.clone())
+            .map_err(|e| OidcError::Discovery(e.to_string()))?;
+        let redirect_url = RedirectUrl::new(config.redirect_url.clone())
+            .map_err(|e| OidcError::Discovery(e.to_string()))?;
+
+        let metadata = CoreProviderMetadata::discover_async(issuer_url, async_http_client).await
+            .map_err(|e| OidcError::Discovery(e.to_string()))?;
+
+        let client = CoreClient::from_provider_metadata(
+            metadata,
+            ClientId::new(config.client_id.clone()),
+            config.client_secret.clone().map(ClientSecret::new),
+        ).set_redirect_uri(redirect_url);
+
+        Ok(Self { client, issuer: config.issuer_url.clone() })
+    }
+
+    /// Build the URL the user must be redirected to, along with the state to keep until the callback
+    pub fn authorize(&self) -> (Url, OidcFlow) {
+        let (pkce_challenge, pkce_verifier) = PkceCodeChallenge::new_random_sha256();
+
+        let (url, csrf_state, nonce) = self.client
+            .authorize_url(CoreAuthenticationFlow::AuthorizationCode, CsrfToken::new_random, Nonce::new_random)
+            .add_scope(Scope::new("email".to_string()))
+            .set_pkce_challenge(pkce_challenge)
+            .url();
+
+        (url, OidcFlow { csrf_state, nonce, pkce_verifier: pkce_verifier.secret().clone() })
+    }
+
+    /// Exchange the authorization code and validate the returned ID token
+    pub async fn exchange(&self, flow: OidcFlow, code: String, state: &str) -> Result<VerifiedIdentity, OidcError> {
+        if flow.csrf_state.secret() != state {
+            return Err(OidcError::StateMismatch);
+        }
+
+        let response = self.client
+            .exchange_code(AuthorizationCode::new(code))
+            .set_pkce_verifier(PkceCodeVerifier::new(flow.pkce_verifier))
+            .request_async(async_http_client).await
+            .map_err(|e| OidcError::TokenExchange(e.to_string()))?;
+
+        // checks the signature, issuer, audience, expiry and nonce
+        let id_token = response.id_token().ok_or(OidcError::MissingIdToken)?;
+        let claims = id_token.claims(&self.client.id_token_verifier(), &flow.nonce)
+            .map_err(|e| OidcError::InvalidIdToken(e.to_string()))?;
+
+        if let Some(expected_hash) = claims.access_token_hash() {
+            let algorithm = id_token.signing_alg().map_err(|e| OidcError::InvalidIdToken(e.to_string()))?;
+            let actual_hash = AccessTokenHash::from_token(response.access_token(), &algorithm)
+                .map_err(|e| OidcError::InvalidIdToken(e.to_string()))?;
+            if actual_hash != *expected_hash {
+                return Err(OidcError::AccessTokenMismatch);
+            }
+        }
+
+        Ok(VerifiedIdentity {
+            issuer: self.issuer.clone(),
+            subject: claims.subject().to_string(),
+            email: claims.email().map(|email| email.to_string()),
+            email_verified: claims.email_verified().unwrap_or(false),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::mock_provider::MockProvider;
+
+    #[tokio::test]
+    async fn test_authorization_code_flow() {
+        let provider = MockProvider::start().await;
+        let oidc = Oidc::discover(&provider.config()).await.unwrap();
+
+        let (url, flow) = oidc.authorize();
+        assert!(url.query_pairs().any(|(k, v)| k == "code_challenge_method" && v == "S256"));
+        let state = flow.csrf_state.secret().clone();
+        provider.issue("jdoe", Some("john.doe@example.com"), true, &flow.nonce).await;
+
+        let identity = oidc.exchange(flow, provider.code(), &state).await.unwrap();
+        assert_eq!(identity, VerifiedIdentity {
+            issuer: provider.issuer(),
+            subject: "jdoe".to_string(),
+            email: Some("john.doe@example.com".to_string()),
+            email_verified: true,
+        });
+    }
+
+    #[tokio::test]
+    async fn test_state_mismatch() {
+        let provider = MockProvider::start().await;
+        let oidc = Oidc::discover(&provider.config()).await.unwrap();
+
+        let (_, flow) = oidc.authorize();
+        provider.issue("jdoe", Some("john.doe@example.com"), true, &flow.nonce).await;
+
+        let res = oidc.exchange(flow, provider.code(), "forged").await;
+        assert_eq!(res.unwrap_err(), OidcError::StateMismatch);
+    }
+
+    #[tokio::test]
+    async fn test_nonce_mismatch() {
+        let provider = MockProvider::start().await;
+        let oidc = Oidc::discover(&provider.config()).await.unwrap();
+
+        let (_, flow) = oidc.authorize();
+        let state = flow.csrf_state.secret().clone();
+        provider.issue("jdoe", Some("john.doe@example.com"), true, &Nonce::new_random()).await;
+
+        let res = oidc.exchange(flow, provider.code(), &state).await;
+        assert!(matches!(res, Err(OidcError::InvalidIdToken(_))));
+    }
+}
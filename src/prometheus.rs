@@ -0,0 +1,128 @@
+//! Prometheus metrics, recorded through the `metrics` facade and served at `/metrics` on the admin
+//! port, so that they are not public
+
+use std::future::Future;
+use std::time::{Duration, Instant};
+use axum::{extract::{MatchedPath, Request, State}, middleware::Next, response::Response, routing::get, Router};
+use diesel::PgConnection;
+use diesel::r2d2::{ConnectionManager, Pool};
+use metrics_exporter_prometheus::{BuildError, Matcher, PrometheusBuilder, PrometheusHandle};
+use tower_sessions::session_store;
+
+pub const HTTP_REQUESTS: &str = "http_requests_total";
+pub const HTTP_REQUEST_DURATION: &str = "http_request_duration_seconds";
+pub const SESSION_STORE_DURATION: &str = "session_store_operation_duration_seconds";
+pub const LOGINS: &str = "logins_total";
+pub const SESSION_CACHE_HITS: &str = "session_cache_hits_total";
+pub const SESSION_CACHE_MISSES: &str = "session_cache_misses_total";
+
+/// Latency buckets, in seconds
+const BUCKETS: &[f64] = &[0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+/// Install the global recorder, whose handle renders the metrics
+pub fn install() -> Result<PrometheusHandle, BuildError> {
+    PrometheusBuilder::new()
+        .set_buckets_for_metric(Matcher::Suffix("_duration_seconds".to_string()), BUCKETS)?
+        .install_recorder()
+}
+
+/// Periodically drain the recorded histogram samples, which are otherwise only drained when scraped
+pub async fn run_upkeep(handle: PrometheusHandle, period: Duration) {
+    let mut interval = tokio::time::interval(period);
+    loop {
+        interval.tick().await;
+        handle.run_upkeep();
+    }
+}
+
+#[derive(Clone)]
+struct MetricsState {
+    handle: PrometheusHandle,
+    db: Pool<ConnectionManager<PgConnection>>,
+}
+
+pub fn router(handle: PrometheusHandle, db: Pool<ConnectionManager<PgConnection>>) -> Router<()> {
+    Router::new()
+        .route("/metrics", get(render))
+        .with_state(MetricsState { handle, db })
+}
+
+async fn render(State(state): State<MetricsState>) -> String {
+    let pool = state.db.state();
+    metrics::gauge!("db_pool_max_size").set(state.db.max_size());
+    metrics::gauge!("db_pool_connections").set(pool.connections);
+    metrics::gauge!("db_pool_idle_connections").set(pool.idle_connections);
+
+    state.handle.render()
+}
+
+/// Middleware counting the requests, and their latency, by method, route and status. Requests
+/// matching no route are grouped, so that scanners do not add a series per path.
+pub async fn track_http(req: Request, next: Next) -> Response {
+    let method = req.method().to_string();
+    let route = req.extensions().get::<MatchedPath>()
+        .map(|path| path.as_str().to_string())
+        .unwrap_or_else(|| "unmatched".to_string());
+
+    let start = Instant::now();
+    let response = next.run(req).await;
+
+    let labels = [("method", method), ("route", route), ("status", response.status().as_u16().to_string())];
+    metrics::counter!(HTTP_REQUESTS, &labels).increment(1);
+    metrics::histogram!(HTTP_REQUEST_DURATION, &labels).record(start.elapsed());
+    response
+}
+
+/// Time an operation of the Postgres session store
+pub async fn time_session_store<T>(
+    operation: &'static str,
+    future: impl Future<Output = session_store::Result<T>>,
+) -> session_store::Result<T> {
+    let start = Instant::now();
+    let result = future.await;
+    let outcome = if result.is_ok() { "ok" } else { "error" };
+    metrics::histogram!(SESSION_STORE_DURATION, "operation" => operation, "outcome" => outcome)
+        .record(start.elapsed());
+    result
+}
+
+/// Count a login attempt, by method and outcome: a success, a failure, or an error
+pub fn record_login<U, E>(method: &'static str, result: &Result<Option<U>, E>) {
+    let outcome = match result {
+        Ok(Some(_)) => "success",
+        Ok(None) => "failure",
+        Err(_) => "error",
+    };
+    metrics::counter!(LOGINS, "method" => method, "outcome" => outcome).increment(1);
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::body::Body;
+    use axum::middleware::from_fn;
+    use tower::ServiceExt;
+    use super::*;
+
+    #[tokio::test]
+    async fn test_track_http() {
+        let recorder = PrometheusBuilder::new()
+            .set_buckets_for_metric(Matcher::Suffix("_duration_seconds".to_string()), BUCKETS).unwrap()
+            .build_recorder();
+        let handle = recorder.handle();
+        let app = Router::new()
+            .route("/users/:id", get(|| async { "miles" }))
+            .layer(from_fn(track_http));
+
+        let _guard = metrics::set_default_local_recorder(&recorder);
+        for uri in ["/users/1", "/users/2", "/wp-login.php"] {
+            app.clone().oneshot(Request::get(uri).body(Body::empty()).unwrap()).await.unwrap();
+        }
+        record_login::<(), ()>("password", &Ok(None));
+
+        let rendered = handle.render();
+        assert!(rendered.contains(r#"http_requests_total{method="GET",route="/users/:id",status="200"} 2"#));
+        assert!(rendered.contains(r#"http_requests_total{method="GET",route="unmatched",status="404"} 1"#));
+        assert!(rendered.contains(r#"http_request_duration_seconds_bucket{method="GET",route="/users/:id",status="200",le="10"} 2"#));
+        assert!(rendered.contains(r#"logins_total{method="password",outcome="failure"} 1"#));
+    }
+}
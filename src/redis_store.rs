@@ -0,0 +1,306 @@
+//! Session store backed by a Redis protocol server (Redis, Valkey...). Sessions are hashes expiring
+//! natively with the session, so no deletion task is needed; a set per user indexes their sessions,
+//! and expires with the last of them. Conditional expiries need Redis 7, or Valkey.
+
+use std::collections::HashMap;
+use std::fmt;
+use async_trait::async_trait;
+use redis::{AsyncCommands, aio::ConnectionManager};
+use time::OffsetDateTime;
+use tower_sessions::{
+    session::{Id, Record},
+    session_store, SessionStore,
+};
+use crate::models::Session;
+use crate::session_key::SessionCipher;
+use crate::store::{record_meta, record_user_id};
+
+const KEY_PREFIX: &str = "anthere:";
+
+#[derive(Clone)]
+pub struct RedisStore {
+    conn: ConnectionManager,
+    cipher: Option<SessionCipher>,
+}
+
+impl fmt::Debug for RedisStore {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("RedisStore").field("cipher", &self.cipher).finish_non_exhaustive()
+    }
+}
+
+impl RedisStore {
+    pub async fn connect(url: &str) -> redis::RedisResult<Self> {
+        let client = redis::Client::open(url)?;
+        let conn = ConnectionManager::new(client).await?;
+        Ok(Self { conn, cipher: None })
+    }
+
+    pub fn with_cipher(self, cipher: Option<SessionCipher>) -> Self {
+        Self { cipher, ..self }
+    }
+
+    /// Check that the server answers
+    pub async fn ping(&self) -> session_store::Result<()> {
+        let mut conn = self.conn.clone();
+        redis::cmd("PING").query_async(&mut conn).await.map_err(adapt_redis_err)
+    }
+
+    /// Active sessions of a user, most recently used first
+    pub async fn user_sessions(&self, owner_id: i32) -> session_store::Result<Vec<Session>> {
+        let mut conn = self.conn.clone();
+        let ids: Vec<String> = conn.smembers(user_key(owner_id)).await.map_err(adapt_redis_err)?;
+
+        let mut sessions = Vec::new();
+        for id in ids {
+            let fields: HashMap<String, Vec<u8>> = conn.hgetall(session_key(&id)).await.map_err(adapt_redis_err)?;
+            match session_from_fields(id.clone(), fields) {
+                Some(session) if session.user_id == Some(owner_id) => sessions.push(session),
+                // expired or logged out, drop it from the index
+                _ => conn.srem(user_key(owner_id), &id).await.map_err(adapt_redis_err)?,
+            }
+        }
+
+        sessions.sort_by_key(|session| std::cmp::Reverse(session.last_seen_at));
+        Ok(sessions)
+    }
+
+    /// Delete the sessions of a user, but the given one, returning how many were deleted
+    pub async fn delete_user_sessions(&self, owner_id: i32, except: Option<&Id>) -> session_store::Result<usize> {
+        let mut conn = self.conn.clone();
+        let ids: Vec<String> = conn.smembers(user_key(owner_id)).await.map_err(adapt_redis_err)?;
+        let except = except.map(|session_id| session_id.to_string());
+
+        let mut deleted = 0;
+        for id in ids.into_iter().filter(|id| Some(id) != except.as_ref()) {
+            let (removed, _): (usize, usize) = redis::pipe()
+                .atomic()
+                .del(session_key(&id))
+                .srem(user_key(owner_id), &id)
+                .query_async(&mut conn).await
+                .map_err(adapt_redis_err)?;
+            deleted += removed;
+        }
+        Ok(deleted)
+    }
+}
+
+#[async_trait]
+impl SessionStore for RedisStore {
+    async fn create(&self, record: &mut Record) -> session_store::Result<()> {
+        let mut conn = self.conn.clone();
+
+        // claim the id, picking another one on collision; the claim expires with the session in case
+        // the save below fails
+        let now = OffsetDateTime::now_utc().unix_timestamp();
+        loop {
+            let key = session_key(&record.id.to_string());
+            let (claimed,): (bool,) = redis::pipe()
+                .atomic()
+                .hset_nx(&key, "created_at", now)
+                .cmd("EXPIREAT").arg(&key).arg(record.expiry_date.unix_timestamp()).arg("NX").ignore()
+                .query_async(&mut conn).await
+                .map_err(adapt_redis_err)?;
+            if claimed {
+                break;
+            }
+            record.id = Id::default();
+        }
+
+        self.save(record).await
+    }
+
+    async fn save(&self, record: &Record) -> session_store::Result<()> {
+        let mut conn = self.conn.clone();
+        let id = record.id.to_string();
+        let key = session_key(&id);
+
+        let data = rmp_serde::to_vec(&record.data)
+            .map_err(|e| session_store::Error::Encode(e.to_string()))?;
+        let data = match &self.cipher {
+            Some(cipher) => cipher.seal(&id, &data),
+            None => data,
+        };
+        let meta = record_meta(record);
+        let user_id = record_user_id(record);
+        let now = OffsetDateTime::now_utc().unix_timestamp();
+
+        let mut pipe = redis::pipe();
+        pipe.atomic()
+            .cmd("HSET").arg(&key)
+            .arg("data").arg(data)
+            .arg("expiry_date").arg(record.expiry_date.unix_timestamp_nanos().to_string())
+            .arg("last_seen_at").arg(now)
+            .ignore()
+            .hset_nx(&key, "created_at", now).ignore()
+            .hdel(&key, &["user_id", "ip", "user_agent"]).ignore();
+        if let Some(user_id) = user_id {
+            // the index expires with the longest session: set its expiry if it has none, or extend it
+            let index = user_key(user_id);
+            let expiry = record.expiry_date.unix_timestamp();
+            pipe.hset(&key, "user_id", user_id).ignore()
+                .sadd(&index, &id).ignore()
+                .cmd("EXPIREAT").arg(&index).arg(expiry).arg("NX").ignore()
+                .cmd("EXPIREAT").arg(&index).arg(expiry).arg("GT").ignore();
+        }
+        if let Some(ip) = meta.ip {
+            pipe.hset(&key, "ip", ip.to_string()).ignore();
+        }
+        if let Some(user_agent) = meta.user_agent {
+            pipe.hset(&key, "user_agent", user_agent).ignore();
+        }
+        pipe.expire_at(&key, record.expiry_date.unix_timestamp()).ignore();
+
+        pipe.query_async(&mut conn).await.map_err(adapt_redis_err)
+    }
+
+    async fn load(&self, session_id: &Id) -> session_store::Result<Option<Record>> {
+        let mut conn = self.conn.clone();
+        let id = session_id.to_string();
+
+        let fields: HashMap<String, Vec<u8>> = conn.hgetall(session_key(&id)).await.map_err(adapt_redis_err)?;
+        let Some(session) = session_from_fields(id, fields) else {
+            return Ok(None);
+        };
+
+        let data = match &self.cipher {
+            Some(cipher) => cipher.open(&session.id, session.data)
+                .map_err(|e| session_store::Error::Backend(e.to_string()))?,
+            None => session.data,
+        };
+        Ok(Some(Record {
+            id: *session_id,
+            data: rmp_serde::from_slice(&data).map_err(|e| session_store::Error::Decode(e.to_string()))?,
+            expiry_date: session.expiry_date,
+        }))
+    }
+
+    async fn delete(&self, session_id: &Id) -> session_store::Result<()> {
+        let mut conn = self.conn.clone();
+        let key = session_key(&session_id.to_string());
+
+        let user_id: Option<i32> = conn.hget(&key, "user_id").await.map_err(adapt_redis_err)?;
+        let mut pipe = redis::pipe();
+        pipe.atomic().del(&key).ignore();
+        if let Some(user_id) = user_id {
+            pipe.srem(user_key(user_id), session_id.to_string()).ignore();
+        }
+        pipe.query_async(&mut conn).await.map_err(adapt_redis_err)
+    }
+}
+
+fn session_key(id: &str) -> String {
+    format!("{}session:{}", KEY_PREFIX, id)
+}
+
+fn user_key(user_id: i32) -> String {
+    format!("{}user_sessions:{}", KEY_PREFIX, user_id)
+}
+
+/// Rebuild a session from its hash, `None` if it expired or was only partially written
+fn session_from_fields(id: String, mut fields: HashMap<String, Vec<u8>>) -> Option<Session> {
+    let text = |value: Vec<u8>| String::from_utf8(value).ok();
+    let timestamp = |value: Option<Vec<u8>>| value.and_then(text)
+        .and_then(|value| value.parse().ok())
+        .and_then(|value| OffsetDateTime::from_unix_timestamp(value).ok());
+
+    let expiry_date = fields.remove("expiry_date").and_then(text)
+        .and_then(|value| value.parse().ok())
+        .and_then(|value| OffsetDateTime::from_unix_timestamp_nanos(value).ok())?;
+
+    Some(Session {
+        id,
+        data: fields.remove("data")?,
+        expiry_date,
+        user_id: fields.remove("user_id").and_then(text).and_then(|value| value.parse().ok()),
+        created_at: timestamp(fields.remove("created_at"))?,
+        last_seen_at: timestamp(fields.remove("last_seen_at"))?,
+        ip: fields.remove("ip").and_then(text)
+            .and_then(|value| value.parse::<std::net::IpAddr>().ok())
+            .map(ipnet::IpNet::from),
+        user_agent: fields.remove("user_agent").and_then(text),
+    })
+}
+
+fn adapt_redis_err(error: redis::RedisError) -> session_store::Error {
+    session_store::Error::Backend(error.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use time::Duration;
+    use crate::store::{AUTH_DATA_KEY, SESSION_META_KEY, SessionMeta};
+    use super::*;
+
+    /// Tests run against the server at `TEST_REDIS_URL`, with `cargo test -- --ignored`
+    async fn get_store() -> RedisStore {
+        let url = std::env::var("TEST_REDIS_URL").expect("TEST_REDIS_URL must be set to run the Redis tests");
+        RedisStore::connect(&url).await.unwrap()
+    }
+
+    fn record(user_id: i32) -> Record {
+        let meta = SessionMeta {
+            ip: Some("192.0.2.1".parse().unwrap()),
+            user_agent: Some("curl/8.8.0".to_string()),
+            touched_at: 0,
+            started_at: 0,
+        };
+        Record {
+            id: Default::default(),
+            data: [
+                (AUTH_DATA_KEY.to_string(), serde_json::json!({ "user_id": user_id, "auth_hash": [] })),
+                (SESSION_META_KEY.to_string(), serde_json::to_value(&meta).unwrap()),
+            ].into(),
+            expiry_date: OffsetDateTime::now_utc() + Duration::minutes(30),
+        }
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a Redis server at TEST_REDIS_URL"]
+    async fn test_create_load_delete() {
+        let store = get_store().await.with_cipher(Some(SessionCipher::new(&[7u8; 32], &[])));
+
+        let mut record = record(-1);
+        store.create(&mut record).await.unwrap();
+        assert_eq!(store.load(&record.id).await.unwrap(), Some(record.clone()));
+
+        let mut conn = store.conn.clone();
+        let ttl: i64 = conn.ttl(session_key(&record.id.to_string())).await.unwrap();
+        assert!(ttl > 0 && ttl <= 30 * 60);
+
+        store.delete(&record.id).await.unwrap();
+        assert_eq!(store.load(&record.id).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a Redis server at TEST_REDIS_URL"]
+    async fn test_user_sessions() {
+        let store = get_store().await;
+        let user_id = -(rand::random::<u16>() as i32) - 2;
+
+        let mut records = Vec::new();
+        for minutes in [30, 60, 10] {
+            let mut record = record(user_id);
+            record.expiry_date = OffsetDateTime::now_utc() + Duration::minutes(minutes);
+            store.create(&mut record).await.unwrap();
+            records.push(record);
+        }
+
+        // the index outlives the longest session, but not forever
+        let mut conn = store.conn.clone();
+        let ttl: i64 = conn.ttl(user_key(user_id)).await.unwrap();
+        assert!(ttl > 30 * 60 && ttl <= 60 * 60);
+
+        let sessions = store.user_sessions(user_id).await.unwrap();
+        assert_eq!(sessions.len(), 3);
+        assert_eq!(sessions[0].ip, Some("192.0.2.1/32".parse().unwrap()));
+        assert_eq!(sessions[0].user_agent.as_deref(), Some("curl/8.8.0"));
+
+        assert_eq!(store.delete_user_sessions(user_id, Some(&records[0].id)).await.unwrap(), 2);
+        let sessions = store.user_sessions(user_id).await.unwrap();
+        assert_eq!(sessions.len(), 1);
+        assert_eq!(sessions[0].id, records[0].id.to_string());
+
+        store.delete(&records[0].id).await.unwrap();
+    }
+}
@@ -0,0 +1,148 @@
+//! Persistent logins ("remember me"). The cookie holds a series, identifying the login, and a token
+//! replaced every time it is used: presenting a replaced token means the cookie was copied, so
+//! every session of its user is revoked. Like API tokens, only a hash of the token is stored.
+
+use axum::extract::{Request, State};
+use axum::http::{HeaderMap, HeaderValue, header::{COOKIE, SET_COOKIE}};
+use axum::middleware::Next;
+use axum::response::Response;
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use rand::RngCore;
+use tower_sessions::cookie::Cookie;
+use crate::auth::{AuthSession, RememberOutcome};
+use crate::config::CookieConfig;
+use crate::store::AppStore;
+
+pub const REMEMBER_COOKIE_NAME: &str = "remember_me";
+const SECRET_LENGTH: usize = 32;
+
+/// Seconds a replaced token is still accepted, for requests sent concurrently with the one
+/// that replaced it
+pub const ROTATION_GRACE_SECONDS: i64 = 60;
+
+fn generate_secret() -> String {
+    let mut bytes = [0u8; SECRET_LENGTH];
+    rand::rngs::OsRng.fill_bytes(&mut bytes);
+    URL_SAFE_NO_PAD.encode(bytes)
+}
+
+/// A new series and its first token
+pub fn generate_series() -> (String, String) {
+    (generate_secret(), generate_secret())
+}
+
+pub fn generate_token() -> String {
+    generate_secret()
+}
+
+pub fn cookie_value(series: &str, token: &str) -> String {
+    format!("{}.{}", series, token)
+}
+
+/// Split a cookie value into its series and token
+pub fn parse_cookie_value(value: &str) -> Option<(&str, &str)> {
+    value.split_once('.').filter(|(series, token)| !series.is_empty() && !token.is_empty())
+}
+
+/// The remember-me cookie of a request, if any
+pub fn request_cookie(headers: &HeaderMap) -> Option<String> {
+    headers.get_all(COOKIE).iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| Cookie::split_parse(value.to_string()).flatten())
+        .find(|cookie| cookie.name() == REMEMBER_COOKIE_NAME)
+        .map(|cookie| cookie.value().to_string())
+}
+
+#[derive(Clone)]
+pub struct RememberMe {
+    store: AppStore,
+    cookie: CookieConfig,
+    /// How long a persistent login lasts without being used, `None` if they are disabled
+    expiry: Option<time::Duration>,
+}
+
+impl RememberMe {
+    pub fn new(store: AppStore, cookie: CookieConfig) -> Self {
+        let expiry = cookie.remember_me_expiry;
+        Self { store, cookie, expiry }
+    }
+
+    pub fn expiry(&self) -> Option<chrono::TimeDelta> {
+        self.expiry.map(|expiry| chrono::TimeDelta::seconds(expiry.whole_seconds()))
+    }
+
+    /// A `Set-Cookie` header value for the given cookie value
+    pub fn set_cookie(&self, value: String) -> Option<HeaderValue> {
+        let cookie = self.build_cookie(value)
+            .max_age(self.expiry.unwrap_or(time::Duration::ZERO));
+        HeaderValue::from_str(&cookie.to_string()).ok()
+    }
+
+    /// A `Set-Cookie` header value removing the cookie
+    pub fn removal_cookie(&self) -> Option<HeaderValue> {
+        let mut cookie = self.build_cookie(String::new()).build();
+        cookie.make_removal();
+        HeaderValue::from_str(&cookie.to_string()).ok()
+    }
+
+    fn build_cookie(&self, value: String) -> tower_sessions::cookie::CookieBuilder<'static> {
+        let mut cookie = Cookie::build((REMEMBER_COOKIE_NAME, value))
+            .path(self.cookie.path.clone())
+            .same_site(self.cookie.same_site)
+            .secure(self.cookie.secure)
+            .http_only(true);
+        if let Some(domain) = &self.cookie.domain {
+            cookie = cookie.domain(domain.clone());
+        }
+        cookie
+    }
+}
+
+/// Middleware, to run after the auth layer, logging in anonymous requests carrying a valid
+/// remember-me cookie, and replacing its token.
+pub async fn remember_user(State(remember): State<RememberMe>, mut req: Request, next: Next) -> Response {
+    let Some(value) = request_cookie(req.headers()) else {
+        return next.run(req).await;
+    };
+    let Some(mut auth_session) = req.extensions().get::<AuthSession>().cloned() else {
+        return next.run(req).await;
+    };
+    if auth_session.user.is_some() {
+        return next.run(req).await;
+    }
+
+    let set_cookie = match remember.expiry() {
+        None => remember.removal_cookie(),
+        Some(expiry) => match auth_session.backend.authenticate_remember_token(&value, expiry).await {
+            Ok(RememberOutcome::Valid(user, rotated)) => {
+                if let Err(e) = auth_session.login(&user).await {
+                    tracing::warn!("failed to log in a remembered user: {}", e);
+                }
+                req.extensions_mut().insert(auth_session);
+                rotated.and_then(|value| remember.set_cookie(value))
+            }
+            Ok(RememberOutcome::Stolen(user_id)) => {
+                tracing::warn!("replaced remember-me token reused, revoking the sessions of user {}", user_id);
+                if let Err(e) = remember.store.delete_user_sessions(user_id, None).await {
+                    tracing::error!("failed to revoke the sessions of user {}: {}", user_id, e);
+                }
+                remember.removal_cookie()
+            }
+            Ok(RememberOutcome::Invalid) => remember.removal_cookie(),
+            Err(e) => {
+                tracing::warn!("failed to check a remember-me token: {}", e);
+                None
+            }
+        },
+    };
+
+    let mut response = next.run(req).await;
+    // the handler may have logged out, removing the cookie
+    let handler_set_cookie = response.headers().get_all(SET_COOKIE).iter()
+        .filter_map(|value| value.to_str().ok())
+        .any(|value| value.starts_with(&format!("{}=", REMEMBER_COOKIE_NAME)));
+    if let Some(set_cookie) = set_cookie.filter(|_| !handler_set_cookie) {
+        response.headers_mut().append(SET_COOKIE, set_cookie);
+    }
+    response
+}
@@ -0,0 +1,42 @@
+//! Request IDs, identifying a request in the logs and in error responses. They are taken from the
+//! `X-Request-Id` header when a proxy set one, generated otherwise, and sent back in the response.
+
+use axum::extract::Request;
+use axum::http::HeaderValue;
+use axum::middleware::Next;
+use axum::response::Response;
+
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+tokio::task_local! {
+    static REQUEST_ID: String;
+}
+
+/// The ID of the request being handled, if any
+pub fn current() -> Option<String> {
+    REQUEST_ID.try_with(String::clone).ok()
+}
+
+fn generate() -> String {
+    format!("{:032x}", rand::random::<u128>())
+}
+
+/// Only reasonable IDs are kept, as they end up in the logs
+fn is_valid(id: &str) -> bool {
+    !id.is_empty() && id.len() <= 64 && id.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+}
+
+/// Middleware making the request ID available to the handler, through `current`
+pub async fn request_id(mut req: Request, next: Next) -> Response {
+    let id = req.headers().get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .filter(|id| is_valid(id))
+        .map(str::to_string)
+        .unwrap_or_else(generate);
+    let header = HeaderValue::from_str(&id).expect("request IDs are valid header values");
+    req.headers_mut().insert(REQUEST_ID_HEADER, header.clone());
+
+    let mut response = REQUEST_ID.scope(id, next.run(req)).await;
+    response.headers_mut().insert(REQUEST_ID_HEADER, header);
+    response
+}
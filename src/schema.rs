@@ -1,10 +1,67 @@
 // @generated automatically by Diesel CLI.
 
+diesel::table! {
+    account_deletions (id) {
+        id -> Int4,
+        user_id -> Int4,
+        requested_at -> Timestamp,
+        erase_after -> Timestamp,
+        canceled_at -> Nullable<Timestamp>,
+        erased_at -> Nullable<Timestamp>,
+        created_at -> Timestamp,
+        updated_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    api_tokens (id) {
+        id -> Int4,
+        user_id -> Int4,
+        name -> Varchar,
+        token_hash -> Bytea,
+        scopes -> Array<Nullable<Text>>,
+        expires_at -> Nullable<Timestamp>,
+        last_used_at -> Nullable<Timestamp>,
+        created_at -> Timestamp,
+        updated_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    oidc_identities (id) {
+        id -> Int4,
+        user_id -> Int4,
+        issuer -> Varchar,
+        subject -> Varchar,
+        created_at -> Timestamp,
+        updated_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    remember_tokens (id) {
+        id -> Int4,
+        user_id -> Int4,
+        series -> Varchar,
+        token_hash -> Bytea,
+        previous_token_hash -> Nullable<Bytea>,
+        rotated_at -> Timestamp,
+        expires_at -> Timestamp,
+        created_at -> Timestamp,
+        updated_at -> Timestamp,
+    }
+}
+
 diesel::table! {
     sessions (id) {
         id -> Text,
         data -> Bytea,
         expiry_date -> Timestamptz,
+        user_id -> Nullable<Int4>,
+        created_at -> Timestamptz,
+        last_seen_at -> Timestamptz,
+        ip -> Nullable<Inet>,
+        user_agent -> Nullable<Varchar>,
     }
 }
 
@@ -25,10 +82,37 @@ diesel::table! {
         confirmed_at -> Nullable<Timestamp>,
         created_at -> Timestamp,
         updated_at -> Timestamp,
+        role -> Varchar,
+        locked_at -> Nullable<Timestamp>,
     }
 }
 
+diesel::table! {
+    webauthn_credentials (id) {
+        id -> Int4,
+        user_id -> Int4,
+        credential_id -> Bytea,
+        public_key -> Bytea,
+        sign_count -> Int8,
+        name -> Varchar,
+        last_used_at -> Nullable<Timestamp>,
+        created_at -> Timestamp,
+        updated_at -> Timestamp,
+    }
+}
+
+diesel::joinable!(api_tokens -> users (user_id));
+diesel::joinable!(oidc_identities -> users (user_id));
+diesel::joinable!(remember_tokens -> users (user_id));
+diesel::joinable!(sessions -> users (user_id));
+diesel::joinable!(webauthn_credentials -> users (user_id));
+
 diesel::allow_tables_to_appear_in_same_query!(
+    account_deletions,
+    api_tokens,
+    oidc_identities,
+    remember_tokens,
     sessions,
     users,
+    webauthn_credentials,
 );
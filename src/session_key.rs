@@ -0,0 +1,291 @@
+//! Keys signing (or encrypting) the session cookie and encrypting the session data at rest.
+//! They are loaded from the configuration so that sessions survive restarts and can be shared
+//! between replicas; previous keys are still accepted during a rotation.
+
+use std::fmt;
+use aes_gcm::{Aes256Gcm, AeadCore, KeyInit, aead::{Aead, OsRng, Payload}};
+use axum::extract::{Request, State};
+use axum::http::{HeaderValue, header::COOKIE};
+use axum::middleware::Next;
+use axum::response::Response;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use sha2::{Digest, Sha256, Sha512};
+use tower_sessions::cookie::{Cookie, CookieJar, Key};
+
+/// Default name of the session cookie
+pub const SESSION_COOKIE_NAME: &str = "id";
+
+#[derive(Clone)]
+pub struct SessionKeys {
+    current: Key,
+    previous: Vec<Key>,
+    /// Whether the cookie is encrypted rather than only signed
+    private: bool,
+    cookie_name: String,
+}
+
+impl SessionKeys {
+    pub fn new(current: Key, previous: Vec<Key>) -> Self {
+        Self { current, previous, private: false, cookie_name: SESSION_COOKIE_NAME.to_string() }
+    }
+
+    pub fn with_private(self, private: bool) -> Self {
+        Self { private, ..self }
+    }
+
+    pub fn with_cookie_name(self, cookie_name: &str) -> Self {
+        Self { cookie_name: cookie_name.to_string(), ..self }
+    }
+
+    pub fn is_private(&self) -> bool {
+        self.private
+    }
+
+    /// The key signing new cookies
+    pub fn current(&self) -> &Key {
+        &self.current
+    }
+
+    /// Rewrite a `Cookie` header so a session cookie signed by a previous key is signed by the
+    /// current one. Returns `None` if nothing needs rewriting.
+    pub fn resign_header(&self, header: &str) -> Option<String> {
+        let mut jar = CookieJar::new();
+        for cookie in Cookie::split_parse(header.to_string()).flatten() {
+            jar.add_original(cookie);
+        }
+
+        if jar.get(&self.cookie_name).is_none() || self.verify(&jar, &self.current).is_some() {
+            return None;
+        }
+        let session_cookie = self.previous.iter()
+            .find_map(|key| self.verify(&jar, key))?;
+        if self.private {
+            jar.private_mut(&self.current).add(session_cookie);
+        } else {
+            jar.signed_mut(&self.current).add(session_cookie);
+        }
+
+        let header = jar.iter()
+            .map(|cookie| format!("{}={}", cookie.name(), cookie.value()))
+            .collect::<Vec<_>>()
+            .join("; ");
+        Some(header)
+    }
+
+    fn verify(&self, jar: &CookieJar, key: &Key) -> Option<Cookie<'static>> {
+        if self.private {
+            jar.private(key).get(&self.cookie_name)
+        } else {
+            jar.signed(key).get(&self.cookie_name)
+        }
+    }
+}
+
+/// Format version of encrypted session data, which can never start a plain `rmp_serde` map
+const SEALED_VERSION: u8 = 1;
+const KEY_ID_LEN: usize = 4;
+const NONCE_LEN: usize = 12;
+
+#[derive(Debug, PartialEq)]
+pub enum CipherError {
+    UnknownKey,
+    Decryption,
+}
+
+impl std::error::Error for CipherError {}
+
+impl fmt::Display for CipherError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CipherError::UnknownKey => write!(f, "session data encrypted with an unknown key"),
+            CipherError::Decryption => write!(f, "session data could not be decrypted"),
+        }
+    }
+}
+
+/// AES-256-GCM encryption of the session data stored by `PgStore`. Data is bound to its session
+/// id and tagged with the id of its key, so data sealed with a previous key can still be read.
+#[derive(Clone)]
+pub struct SessionCipher {
+    keys: Vec<([u8; KEY_ID_LEN], Aes256Gcm)>,
+}
+
+impl fmt::Debug for SessionCipher {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SessionCipher").field("keys", &self.keys.len()).finish_non_exhaustive()
+    }
+}
+
+impl SessionCipher {
+    /// Keys are decoded base64 strings of at least 32 bytes, e.g. from `generate_key`
+    pub fn new(current: &[u8], previous: &[Vec<u8>]) -> Self {
+        let keys = std::iter::once(current)
+            .chain(previous.iter().map(Vec::as_slice))
+            .map(|key| {
+                let key = Sha256::digest(key);
+                let id = Sha256::digest(key);
+                (id[..KEY_ID_LEN].try_into().unwrap(), Aes256Gcm::new(&key))
+            })
+            .collect();
+        Self { keys }
+    }
+
+    pub fn seal(&self, session_id: &str, data: &[u8]) -> Vec<u8> {
+        let (key_id, cipher) = &self.keys[0];
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let ciphertext = cipher.encrypt(&nonce, Payload { msg: data, aad: session_id.as_bytes() })
+            .expect("AES-GCM encryption cannot fail for session sized data");
+
+        let mut sealed = Vec::with_capacity(1 + KEY_ID_LEN + NONCE_LEN + ciphertext.len());
+        sealed.push(SEALED_VERSION);
+        sealed.extend_from_slice(key_id);
+        sealed.extend_from_slice(&nonce);
+        sealed.extend_from_slice(&ciphertext);
+        sealed
+    }
+
+    /// Decrypt data sealed by `seal`, passing through data stored before encryption was enabled
+    pub fn open(&self, session_id: &str, data: Vec<u8>) -> Result<Vec<u8>, CipherError> {
+        if !is_sealed(&data) {
+            return Ok(data);
+        }
+        let (key_id, rest) = data[1..].split_at(KEY_ID_LEN);
+        let (nonce, ciphertext) = rest.split_at(NONCE_LEN);
+        let (_, cipher) = self.keys.iter()
+            .find(|(id, _)| id == key_id)
+            .ok_or(CipherError::UnknownKey)?;
+        cipher.decrypt(nonce.into(), Payload { msg: ciphertext, aad: session_id.as_bytes() })
+            .or(Err(CipherError::Decryption))
+    }
+}
+
+/// Whether stored session data was encrypted by a `SessionCipher`
+pub fn is_sealed(data: &[u8]) -> bool {
+    data.len() > 1 + KEY_ID_LEN + NONCE_LEN && data[0] == SEALED_VERSION
+}
+
+/// Generate a random key, encoded as expected by `parse_key`
+pub fn generate_key() -> String {
+    BASE64.encode(Key::generate().master())
+}
+
+/// Decode a base64 encoded key of at least 64 bytes
+pub fn parse_key(encoded: &str) -> Result<Key, &'static str> {
+    let bytes = BASE64.decode(encoded.trim()).or(Err("session key is not valid base64"))?;
+    Key::try_from(bytes.as_slice()).or(Err("session key must be at least 64 bytes long"))
+}
+
+/// A key for another purpose, derived from a session key
+pub fn derive_key(key: &Key, purpose: &str) -> Key {
+    let derived = Sha512::new().chain_update(purpose).chain_update(key.master()).finalize();
+    Key::from(&derived)
+}
+
+/// Decode a base64 encoded session data key of at least 32 bytes
+pub fn parse_data_key(encoded: &str) -> Result<Vec<u8>, &'static str> {
+    let bytes = BASE64.decode(encoded.trim()).or(Err("session data key is not valid base64"))?;
+    if bytes.len() < 32 {
+        return Err("session data key must be at least 32 bytes long");
+    }
+    Ok(bytes)
+}
+
+/// Middleware, to run before the session layer, accepting session cookies signed by a previous
+/// key. The session layer signs the cookie with the current key the next time it is sent back.
+pub async fn accept_previous_keys(State(keys): State<SessionKeys>, mut req: Request, next: Next) -> Response {
+    let header = req.headers().get_all(COOKIE).iter()
+        .filter_map(|value| value.to_str().ok())
+        .collect::<Vec<_>>()
+        .join("; ");
+
+    if let Some(resigned) = keys.resign_header(&header) {
+        if let Ok(value) = HeaderValue::from_str(&resigned) {
+            req.headers_mut().remove(COOKIE);
+            req.headers_mut().insert(COOKIE, value);
+        }
+    }
+
+    next.run(req).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn signed_header(key: &Key, value: &str) -> String {
+        let mut jar = CookieJar::new();
+        jar.signed_mut(key).add(Cookie::new(SESSION_COOKIE_NAME, value.to_string()));
+        format!("theme=dark; {}={}", SESSION_COOKIE_NAME, jar.get(SESSION_COOKIE_NAME).unwrap().value())
+    }
+
+    #[test]
+    fn test_parse_key() {
+        let key = generate_key();
+        assert_eq!(parse_key(&key).unwrap().master(), BASE64.decode(&key).unwrap().as_slice());
+        assert!(parse_key("c2hvcnQ=").is_err());
+        assert!(parse_key("not base64!").is_err());
+    }
+
+    #[test]
+    fn test_resign_previous_key() {
+        let (current, previous) = (Key::generate(), Key::generate());
+        let keys = SessionKeys::new(current.clone(), vec![previous.clone()]);
+
+        let resigned = keys.resign_header(&signed_header(&previous, "session-id")).unwrap();
+
+        let mut jar = CookieJar::new();
+        for cookie in Cookie::split_parse(resigned).flatten() {
+            jar.add_original(cookie);
+        }
+        assert_eq!(jar.signed(&current).get(SESSION_COOKIE_NAME).unwrap().value(), "session-id");
+        assert_eq!(jar.get("theme").unwrap().value(), "dark");
+    }
+
+    #[test]
+    fn test_resign_private_cookie() {
+        let (current, previous) = (Key::generate(), Key::generate());
+        let keys = SessionKeys::new(current.clone(), vec![previous.clone()]).with_private(true);
+
+        let mut jar = CookieJar::new();
+        jar.private_mut(&previous).add(Cookie::new(SESSION_COOKIE_NAME, "session-id"));
+        let header = format!("{}={}", SESSION_COOKIE_NAME, jar.get(SESSION_COOKIE_NAME).unwrap().value());
+        assert!(!header.contains("session-id"));
+
+        let resigned = keys.resign_header(&header).unwrap();
+        let mut jar = CookieJar::new();
+        for cookie in Cookie::split_parse(resigned).flatten() {
+            jar.add_original(cookie);
+        }
+        assert_eq!(jar.private(&current).get(SESSION_COOKIE_NAME).unwrap().value(), "session-id");
+    }
+
+    #[test]
+    fn test_session_cipher() {
+        let (old_key, new_key) = (vec![1u8; 32], vec![2u8; 64]);
+        let old = SessionCipher::new(&old_key, &[]);
+        let rotated = SessionCipher::new(&new_key, &[old_key]);
+
+        let sealed = old.seal("session-id", b"authenticity_token");
+        assert!(is_sealed(&sealed));
+        assert!(!sealed.windows(5).any(|w| w == b"token"));
+
+        assert_eq!(rotated.open("session-id", sealed.clone()).unwrap(), b"authenticity_token");
+        assert_eq!(rotated.open("other-id", sealed.clone()), Err(CipherError::Decryption));
+        assert_eq!(SessionCipher::new(&new_key, &[]).open("session-id", sealed), Err(CipherError::UnknownKey));
+
+        // data stored before encryption was enabled
+        let plain = rmp_serde::to_vec(&std::collections::HashMap::from([("a", 1)])).unwrap();
+        assert_eq!(rotated.open("session-id", plain.clone()).unwrap(), plain);
+    }
+
+    #[test]
+    fn test_resign_current_or_unknown_key() {
+        let current = Key::generate();
+        let keys = SessionKeys::new(current.clone(), vec![Key::generate()]);
+
+        assert_eq!(keys.resign_header(&signed_header(&current, "session-id")), None);
+        assert_eq!(keys.resign_header(&signed_header(&Key::generate(), "session-id")), None);
+        assert_eq!(keys.resign_header("theme=dark"), None);
+    }
+}
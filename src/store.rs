@@ -1,23 +1,91 @@
+use std::net::IpAddr;
 use async_trait::async_trait;
 use diesel::{Connection, ExpressionMethods, PgConnection, RunQueryDsl};
 use diesel::r2d2::ConnectionManager;
 use r2d2::Pool;
+use serde::{Deserialize, Serialize};
 use tower_sessions::{
     session::{Id, Record},
     session_store, ExpiredDeletion, SessionStore,
 };
-use crate::models::Session;
+use crate::caching_store::{CacheStats, CachingStore};
+use crate::db::{run_blocking, BlockingError};
+use crate::models::{NewSession, Session};
+use crate::prometheus::time_session_store;
+use crate::redis_store::RedisStore;
+use crate::session_key::SessionCipher;
+
+/// Key under which axum-login stores the id of the logged-in user
+pub const AUTH_DATA_KEY: &str = "axum-login.data";
+pub const SESSION_META_KEY: &str = "session_meta";
+
+/// Client metadata, kept up to date in the session data by the app and copied to columns on save
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct SessionMeta {
+    pub ip: Option<IpAddr>,
+    pub user_agent: Option<String>,
+    /// Unix timestamp of the last update, used to throttle `last_seen_at` updates
+    pub touched_at: i64,
+    /// Unix timestamp of the first request after login, for the absolute expiry
+    #[serde(default)]
+    pub started_at: i64,
+}
 
 #[derive(Clone, Debug)]
 pub struct PgStore {
     db: Pool<ConnectionManager<PgConnection>>,
+    /// Encrypts the session data at rest when set
+    cipher: Option<SessionCipher>,
 }
 
 impl PgStore {
 
     pub fn new(db: Pool<ConnectionManager<PgConnection>>) -> Self {
-        Self { db }
+        Self { db, cipher: None }
+    }
+
+    pub fn with_cipher(self, cipher: Option<SessionCipher>) -> Self {
+        Self { cipher, ..self }
+    }
+
+    /// Active sessions of a user, most recently used first
+    pub async fn user_sessions(&self, owner_id: i32) -> session_store::Result<Vec<Session>> {
+        use crate::schema::sessions::dsl::*;
+        use diesel::{dsl::now, prelude::*};
+
+        run_blocking(&self.db, move |conn| {
+            sessions
+                .filter(user_id.eq(owner_id))
+                .filter(expiry_date.gt(now))
+                .select(Session::as_select())
+                .order(last_seen_at.desc())
+                .load(conn)
+                .map_err(adapt_diesel_err)
+        }).await
     }
+
+    /// Delete the sessions of a user, but the given one, returning how many were deleted
+    pub async fn delete_user_sessions(&self, owner_id: i32, except: Option<&Id>) -> session_store::Result<usize> {
+        use crate::schema::sessions::dsl::*;
+        use diesel::prelude::*;
+
+        let except = except.map(|session_id| session_id.to_string()).unwrap_or_default();
+        run_blocking(&self.db, move |conn| {
+            diesel::delete(sessions.filter(user_id.eq(owner_id)).filter(id.ne(except)))
+                .execute(conn)
+                .map_err(adapt_diesel_err)
+        }).await
+    }
+
+    /// Check that the database answers, without touching the sessions
+    pub async fn ping(&self) -> session_store::Result<()> {
+        use diesel::sql_query;
+
+        run_blocking(&self.db, |conn| {
+            sql_query("SELECT 1").execute(conn).map(|_| ()).map_err(adapt_diesel_err)
+        }).await
+    }
+
     fn id_exists(&self, conn: &mut PgConnection, session_id: &Id) -> diesel::QueryResult<bool> {
         use crate::schema::sessions::dsl::*;
         use diesel::{select, dsl::exists, prelude::*};
@@ -33,13 +101,24 @@ impl PgStore {
         record: &Record,
     ) -> diesel::QueryResult<()> {
         use crate::schema::sessions::dsl::*;
-        use diesel::upsert::excluded;
+        use diesel::{dsl::now, upsert::excluded};
+
+        let meta = record_meta(record);
 
-        let new_session = Session {
+        let session_data = rmp_serde::to_vec(&record.data)
+            .map_err(adapt_serial_err)?;
+        let session_data = match &self.cipher {
+            Some(cipher) => cipher.seal(&record.id.to_string(), &session_data),
+            None => session_data,
+        };
+
+        let new_session = NewSession {
             id: record.id.to_string(),
-            data: rmp_serde::to_vec(&record.data)
-                .map_err(adapt_serial_err)?,
-            expiry_date: record.expiry_date
+            data: session_data,
+            expiry_date: record.expiry_date,
+            user_id: record_user_id(record),
+            ip: meta.ip.map(ipnet::IpNet::from),
+            user_agent: meta.user_agent,
         };
 
         diesel::insert_into(sessions)
@@ -48,7 +127,11 @@ impl PgStore {
             .do_update()
             .set((
                 data.eq(excluded(data)),
-                expiry_date.eq(excluded(expiry_date))
+                expiry_date.eq(excluded(expiry_date)),
+                user_id.eq(excluded(user_id)),
+                ip.eq(excluded(ip)),
+                user_agent.eq(excluded(user_agent)),
+                last_seen_at.eq(now)
             ))
             .execute(conn)?;
 
@@ -57,85 +140,202 @@ impl PgStore {
 
 }
 
-#[async_trait]
-impl ExpiredDeletion for PgStore {
-    async fn delete_expired(&self) -> session_store::Result<()> {
-        use crate::schema::sessions::dsl::*;
-        use diesel::{dsl::now, prelude::*};
+/// The session store selected by the configuration
+#[derive(Clone, Debug)]
+pub enum AppStore {
+    Postgres(PgStore),
+    CachedPostgres(CachingStore<PgStore>),
+    Redis(RedisStore),
+}
 
-        let conn = &mut self.db.get().map_err(adapt_backend_err)?;
+impl AppStore {
+    pub async fn user_sessions(&self, owner_id: i32) -> session_store::Result<Vec<Session>> {
+        match self {
+            AppStore::Postgres(store) => store.user_sessions(owner_id).await,
+            AppStore::CachedPostgres(store) => store.inner().user_sessions(owner_id).await,
+            AppStore::Redis(store) => store.user_sessions(owner_id).await,
+        }
+    }
 
-        diesel::delete(sessions.filter(expiry_date.lt(now)))
-            .execute(conn)
-            .map_err(adapt_backend_err)?;
-        Ok(())
+    pub async fn delete_user_sessions(&self, owner_id: i32, except: Option<&Id>) -> session_store::Result<usize> {
+        match self {
+            AppStore::Postgres(store) => store.delete_user_sessions(owner_id, except).await,
+            AppStore::CachedPostgres(store) => {
+                store.evict_user(owner_id, except);
+                store.inner().delete_user_sessions(owner_id, except).await
+            }
+            AppStore::Redis(store) => store.delete_user_sessions(owner_id, except).await,
+        }
+    }
+
+    /// Check that the backing store answers, without reading or writing a session, so that probes
+    /// neither evict cached sessions nor show in the store metrics
+    pub async fn ping(&self) -> session_store::Result<()> {
+        match self {
+            AppStore::Postgres(store) => store.ping().await,
+            AppStore::CachedPostgres(store) => store.inner().ping().await,
+            AppStore::Redis(store) => store.ping().await,
+        }
+    }
+
+    pub fn cache_stats(&self) -> Option<CacheStats> {
+        match self {
+            AppStore::CachedPostgres(store) => Some(store.stats()),
+            _ => None,
+        }
     }
 }
 
 #[async_trait]
-impl SessionStore for PgStore {
+impl SessionStore for AppStore {
     async fn create(&self, record: &mut Record) -> session_store::Result<()> {
-        let conn = &mut self.db.get().map_err(adapt_backend_err)?;
+        match self {
+            AppStore::Postgres(store) => store.create(record).await,
+            AppStore::CachedPostgres(store) => store.create(record).await,
+            AppStore::Redis(store) => store.create(record).await,
+        }
+    }
 
-        conn.transaction(|conn| {
-            while self.id_exists(conn, &record.id)? {
-                record.id = Id::default();
-            }
+    async fn save(&self, record: &Record) -> session_store::Result<()> {
+        match self {
+            AppStore::Postgres(store) => store.save(record).await,
+            AppStore::CachedPostgres(store) => store.save(record).await,
+            AppStore::Redis(store) => store.save(record).await,
+        }
+    }
 
-            // remove nanoseconds data as PgSQL does not support precision over the millisecond
-            let format = time::macros::format_description!("[year]-[month]-[day]T[hour]:[minute]:[second].[subsecond digits:6][offset_hour]:[offset_minute]");
-            let new_expiry = time::OffsetDateTime::parse(
-                record.expiry_date
-                    .format(format)
-                    .map_err(adapt_serial_err)?
-                    .as_str(),
-                format)
-                .map_err(adapt_serial_err)?;
-            record.expiry_date = new_expiry;
-            
-            self.save_with_conn(conn, record)?;
+    async fn load(&self, session_id: &Id) -> session_store::Result<Option<Record>> {
+        match self {
+            AppStore::Postgres(store) => store.load(session_id).await,
+            AppStore::CachedPostgres(store) => store.load(session_id).await,
+            AppStore::Redis(store) => store.load(session_id).await,
+        }
+    }
+
+    async fn delete(&self, session_id: &Id) -> session_store::Result<()> {
+        match self {
+            AppStore::Postgres(store) => store.delete(session_id).await,
+            AppStore::CachedPostgres(store) => store.delete(session_id).await,
+            AppStore::Redis(store) => store.delete(session_id).await,
+        }
+    }
+}
+
+#[async_trait]
+impl ExpiredDeletion for PgStore {
+    async fn delete_expired(&self) -> session_store::Result<()> {
+        time_session_store("delete_expired", async {
+            use crate::schema::sessions::dsl::*;
+            use diesel::{dsl::now, prelude::*};
+
+            run_blocking(&self.db, |conn| {
+                diesel::delete(sessions.filter(expiry_date.lt(now)))
+                    .execute(conn)
+                    .map_err(adapt_backend_err)?;
+                Ok(())
+            }).await
+        }).await
+    }
+}
+
+#[async_trait]
+impl SessionStore for PgStore {
+    async fn create(&self, record: &mut Record) -> session_store::Result<()> {
+        time_session_store("create", async {
+            let (store, mut new_record) = (self.clone(), record.clone());
+            *record = run_blocking(&self.db, move |conn| conn.transaction(|conn| {
+                let record = &mut new_record;
+                while store.id_exists(conn, &record.id)? {
+                    record.id = Id::default();
+                }
+
+                // remove nanoseconds data as PgSQL does not support precision over the millisecond
+                let format = time::macros::format_description!("[year]-[month]-[day]T[hour]:[minute]:[second].[subsecond digits:6][offset_hour]:[offset_minute]");
+                let new_expiry = time::OffsetDateTime::parse(
+                    record.expiry_date
+                        .format(format)
+                        .map_err(adapt_serial_err)?
+                        .as_str(),
+                    format)
+                    .map_err(adapt_serial_err)?;
+                record.expiry_date = new_expiry;
+                
+                store.save_with_conn(conn, record)?;
+
+                Ok(new_record)
+            }).map_err(adapt_diesel_err)).await?;
 
             Ok(())
-        }).map_err(adapt_diesel_err)
+        }).await
     }
 
     async fn save(&self, record: &Record) -> session_store::Result<()> {
-        let conn = &mut self.db.get().map_err(adapt_backend_err)?;
-
-        self.save_with_conn(conn, record).map_err(adapt_diesel_err)
+        time_session_store("save", async {
+            let (store, record) = (self.clone(), record.clone());
+            run_blocking(&self.db, move |conn| {
+                store.save_with_conn(conn, &record).map_err(adapt_diesel_err)
+            }).await
+        }).await
     }
 
     async fn load(&self, session_id: &Id) -> session_store::Result<Option<Record>> {
-        use crate::schema::sessions::dsl::*;
-        use diesel::{prelude::*};
-
-        let conn = &mut self.db.get().map_err(adapt_backend_err)?;
+        time_session_store("load", async {
+            use crate::schema::sessions::dsl::*;
+            use diesel::{prelude::*};
+
+            let session_id = session_id.to_string();
+            let session = run_blocking(&self.db, move |conn| {
+                sessions
+                    .filter(id.eq(session_id))
+                    .select(Session::as_select())
+                    .get_result(conn)
+                    .optional()
+                    .map_err(adapt_diesel_err)
+            }).await?;
+
+            let Some(mut session) = session else {
+                return Ok(None);
+            };
+            if let Some(cipher) = &self.cipher {
+                session.data = cipher.open(&session.id, session.data).map_err(adapt_backend_err)?;
+            }
 
-        let session = sessions
-            .filter(id.eq(session_id.to_string()))
-            .select(Session::as_select())
-            .get_result(conn)
-            .optional()
-            .map_err(adapt_diesel_err)?
-            .map(adapt_session_result);
-        
-        if let Some(session) = session {
-            Ok(Some(session.map_err(adapt_backend_err)?))
-        } else {
-            Ok(None)
-        }
+            Ok(Some(adapt_session_result(session).map_err(adapt_backend_err)?))
+        }).await
     }
 
     async fn delete(&self, session_id: &Id) -> session_store::Result<()> {
-        use crate::schema::sessions::dsl::*;
-        use diesel::prelude::*;
+        time_session_store("delete", async {
+            use crate::schema::sessions::dsl::*;
+            use diesel::prelude::*;
+
+            let session_id = session_id.to_string();
+            run_blocking(&self.db, move |conn| {
+                diesel::delete(sessions.filter(id.eq(session_id)))
+                    .execute(conn)
+                    .map_err(adapt_backend_err)?;
+                Ok(())
+            }).await
+        }).await
+    }
+}
 
-        let conn = &mut self.db.get().map_err(adapt_backend_err)?;
+pub(crate) fn record_meta(record: &Record) -> SessionMeta {
+    record.data.get(SESSION_META_KEY)
+        .and_then(|meta| serde_json::from_value(meta.clone()).ok())
+        .unwrap_or_default()
+}
 
-        diesel::delete(sessions.filter(id.eq(session_id.to_string())))
-            .execute(conn)
-            .map_err(adapt_backend_err)?;
-        Ok(())
+pub(crate) fn record_user_id(record: &Record) -> Option<i32> {
+    record.data.get(AUTH_DATA_KEY)
+        .and_then(|auth_data| auth_data.get("user_id"))
+        .and_then(|user_id| user_id.as_i64())
+        .and_then(|user_id| i32::try_from(user_id).ok())
+}
+
+impl From<BlockingError> for session_store::Error {
+    fn from(error: BlockingError) -> Self {
+        adapt_backend_err(error)
     }
 }
 
@@ -254,4 +454,76 @@ mod tests {
             assert_ne!(record1.id, record2.id); // IDs should be different
         }.boxed()).await;
     }
-}
+
+    #[tokio::test]
+    async fn test_save_metadata() {
+        let db = get_db_pool();
+        db.run_test(|pool| async move {
+            use crate::schema::users::dsl::*;
+            use diesel::prelude::*;
+
+            let conn = &mut pool.get().unwrap();
+            let owner: i32 = users.filter(email.eq("miles.davis@trumpet.com")).select(id).first(conn).unwrap();
+
+            let store = PgStore::new(pool);
+            let meta = SessionMeta {
+                ip: Some("192.0.2.1".parse().unwrap()),
+                user_agent: Some("curl/8.8.0".to_string()),
+                touched_at: 0,
+                started_at: 0,
+            };
+            let mut records = Vec::new();
+            for _ in 0..3 {
+                let mut record = Record {
+                    id: Default::default(),
+                    data: [
+                        (AUTH_DATA_KEY.to_string(), serde_json::json!({ "user_id": owner, "auth_hash": [] })),
+                        (SESSION_META_KEY.to_string(), serde_json::to_value(&meta).unwrap()),
+                    ].into(),
+                    expiry_date: OffsetDateTime::now_utc() + Duration::minutes(30),
+                };
+                store.create(&mut record).await.unwrap();
+                records.push(record);
+            }
+
+            let user_sessions = store.user_sessions(owner).await.unwrap();
+            assert_eq!(user_sessions.len(), 3);
+            assert_eq!(user_sessions[0].ip, Some("192.0.2.1/32".parse().unwrap()));
+            assert_eq!(user_sessions[0].user_agent.as_deref(), Some("curl/8.8.0"));
+
+            assert_eq!(store.delete_user_sessions(owner, Some(&records[0].id)).await.unwrap(), 2);
+            let user_sessions = store.user_sessions(owner).await.unwrap();
+            assert_eq!(user_sessions.len(), 1);
+            assert_eq!(user_sessions[0].id, records[0].id.to_string());
+        }.boxed()).await;
+    }
+
+    #[tokio::test]
+    async fn test_encrypted_data() {
+        let db = get_db_pool();
+        db.run_test(|pool| async move {
+            use crate::schema::sessions::dsl::*;
+            use diesel::prelude::*;
+
+            let store = PgStore::new(pool.clone()).with_cipher(Some(SessionCipher::new(&[7u8; 32], &[])));
+            let mut record = Record {
+                id: Default::default(),
+                data: [("authenticity_token".to_string(), serde_json::json!("secret-token"))].into(),
+                expiry_date: OffsetDateTime::now_utc() + Duration::minutes(30),
+            };
+            store.create(&mut record).await.unwrap();
+
+            let conn = &mut pool.get().unwrap();
+            let stored: Vec<u8> = sessions.find(record.id.to_string()).select(data).first(conn).unwrap();
+            assert!(crate::session_key::is_sealed(&stored));
+            assert!(!stored.windows(12).any(|w| w == b"secret-token"));
+
+            assert_eq!(store.load(&record.id).await.unwrap(), Some(record.clone()));
+
+            // a plain store cannot read it, a rotated one can
+            assert!(PgStore::new(pool.clone()).load(&record.id).await.is_err());
+            let rotated = PgStore::new(pool).with_cipher(Some(SessionCipher::new(&[8u8; 32], &[vec![7u8; 32]])));
+            assert_eq!(rotated.load(&record.id).await.unwrap(), Some(record));
+        }.boxed()).await;
+    }
+}
\ No newline at end of file
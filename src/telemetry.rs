@@ -0,0 +1,56 @@
+//! Logging setup, and the spans following a request or a job through the async runtime and
+//! the blocking thread pool
+
+use std::future::Future;
+use axum::extract::{MatchedPath, Request};
+use tokio::task::JoinHandle;
+use tracing::{Instrument, Span};
+use crate::config::{LogConfig, LogFormat};
+use crate::request_id::REQUEST_ID_HEADER;
+
+/// Install the global subscriber. JSON logs include the fields of the enclosing spans, e.g. the
+/// request ID, so that every line about a request can be found.
+pub fn init(config: &LogConfig) {
+    let builder = tracing_subscriber::fmt().with_max_level(config.level);
+    match config.format {
+        LogFormat::Text => builder.init(),
+        LogFormat::Json => builder.json().with_current_span(false).with_span_list(true).init(),
+    }
+}
+
+/// The span of an HTTP request, whose `user_id` is recorded once the user is known
+pub fn request_span(req: &Request) -> Span {
+    let route = req.extensions().get::<MatchedPath>()
+        .map(MatchedPath::as_str)
+        .unwrap_or("");
+    let request_id = req.headers().get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or("");
+
+    tracing::info_span!(
+        "request",
+        method = %req.method(),
+        route,
+        request_id,
+        user_id = tracing::field::Empty,
+    )
+}
+
+/// `tokio::task::spawn_blocking`, keeping the current span
+pub fn spawn_blocking<F, R>(task: F) -> JoinHandle<R>
+where
+    F: FnOnce() -> R + Send + 'static,
+    R: Send + 'static,
+{
+    let span = Span::current();
+    tokio::task::spawn_blocking(move || span.in_scope(task))
+}
+
+/// Spawn a background job in its own span
+pub fn spawn_job<F>(name: &'static str, job: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    tokio::task::spawn(job.instrument(tracing::info_span!("job", job = name)))
+}
@@ -0,0 +1,672 @@
+//! This module implements the relying party side of the WebAuthn registration and
+//! authentication ceremonies, for ES256 (P-256) passkeys without attestation, which must verify the
+//! user (PIN, biometrics) rather than only sense their presence.
+
+use std::fmt;
+use base64::Engine;
+use base64::engine::{DecodePaddingMode, GeneralPurpose, GeneralPurposeConfig};
+use ciborium::Value;
+use p256::ecdsa::{Signature, VerifyingKey, signature::Verifier};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use crate::models::{User, WebauthnCredential};
+
+#[cfg(test)]
+pub mod soft_authenticator;
+
+const CHALLENGE_LENGTH: usize = 32;
+const CEREMONY_TIMEOUT_MS: u32 = 60_000;
+// COSE algorithm identifier for ECDSA w/ SHA-256
+const COSE_ALG_ES256: i128 = -7;
+
+const FLAG_USER_PRESENT: u8 = 0x01;
+const FLAG_USER_VERIFIED: u8 = 0x04;
+const FLAG_ATTESTED_CREDENTIAL_DATA: u8 = 0x40;
+
+// Browsers serialize binary fields as unpadded base64url, but be lenient on input
+const BASE64_URL: GeneralPurpose = GeneralPurpose::new(
+    &base64::alphabet::URL_SAFE,
+    GeneralPurposeConfig::new()
+        .with_encode_padding(false)
+        .with_decode_padding_mode(DecodePaddingMode::Indifferent),
+);
+
+mod b64url {
+    use base64::Engine;
+    use serde::{Deserialize, Deserializer, Serializer, de};
+    use super::BASE64_URL;
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&BASE64_URL.encode(bytes))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        let encoded = String::deserialize(deserializer)?;
+        BASE64_URL.decode(encoded).map_err(de::Error::custom)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum WebauthnError {
+    Malformed(String),
+    TypeMismatch,
+    ChallengeMismatch,
+    OriginMismatch,
+    RpIdMismatch,
+    UserNotPresent,
+    UserNotVerified,
+    CredentialTypeMismatch,
+    ChallengeExpired,
+    UnsupportedKey,
+    InvalidSignature,
+    CounterRegression,
+}
+
+impl std::error::Error for WebauthnError {}
+
+impl fmt::Display for WebauthnError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            WebauthnError::Malformed(e) => write!(f, "Malformed WebAuthn response: {}", e),
+            WebauthnError::TypeMismatch => write!(f, "Unexpected client data type"),
+            WebauthnError::ChallengeMismatch => write!(f, "Challenge does not match"),
+            WebauthnError::OriginMismatch => write!(f, "Origin does not match"),
+            WebauthnError::RpIdMismatch => write!(f, "Relying party ID does not match"),
+            WebauthnError::UserNotPresent => write!(f, "User presence flag is not set"),
+            WebauthnError::UserNotVerified => write!(f, "User verification flag is not set"),
+            WebauthnError::CredentialTypeMismatch => write!(f, "Credential is not a public key credential"),
+            WebauthnError::ChallengeExpired => write!(f, "Challenge expired"),
+            WebauthnError::UnsupportedKey => write!(f, "Only ES256 (P-256) credentials are supported"),
+            WebauthnError::InvalidSignature => write!(f, "Assertion signature is invalid"),
+            WebauthnError::CounterRegression => write!(f, "Signature counter did not increase, the authenticator may be cloned"),
+        }
+    }
+}
+
+fn malformed<T: fmt::Display>(error: T) -> WebauthnError {
+    WebauthnError::Malformed(error.to_string())
+}
+
+/// A random challenge, kept in the session between the start and the end of a ceremony, which
+/// must end within the timeout given to the client
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Challenge {
+    #[serde(with = "b64url")]
+    value: Vec<u8>,
+    /// Unix timestamp, in milliseconds
+    issued_at: i64,
+}
+
+impl Challenge {
+    fn generate() -> Self {
+        let mut value = vec![0u8; CHALLENGE_LENGTH];
+        rand::rngs::OsRng.fill_bytes(&mut value);
+        Challenge { value, issued_at: chrono::Utc::now().timestamp_millis() }
+    }
+
+    fn is_expired(&self) -> bool {
+        chrono::Utc::now().timestamp_millis() - self.issued_at > i64::from(CEREMONY_TIMEOUT_MS)
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RelyingParty {
+    pub id: String,
+    pub name: String,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UserEntity {
+    #[serde(with = "b64url")]
+    pub id: Vec<u8>,
+    pub name: String,
+    pub display_name: String,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CredentialParameters {
+    #[serde(rename = "type")]
+    pub type_: String,
+    pub alg: i32,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CredentialDescriptor {
+    #[serde(rename = "type")]
+    pub type_: String,
+    #[serde(with = "b64url")]
+    pub id: Vec<u8>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AuthenticatorSelection {
+    pub resident_key: String,
+    pub user_verification: String,
+}
+
+/// JSON form of `PublicKeyCredentialCreationOptions`, as expected by `PublicKeyCredential.parseCreationOptionsFromJSON`
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CreationOptions {
+    pub rp: RelyingParty,
+    pub user: UserEntity,
+    #[serde(with = "b64url")]
+    pub challenge: Vec<u8>,
+    pub pub_key_cred_params: Vec<CredentialParameters>,
+    pub timeout: u32,
+    pub attestation: String,
+    pub exclude_credentials: Vec<CredentialDescriptor>,
+    pub authenticator_selection: AuthenticatorSelection,
+}
+
+/// JSON form of `PublicKeyCredentialRequestOptions`, as expected by `PublicKeyCredential.parseRequestOptionsFromJSON`
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RequestOptions {
+    #[serde(with = "b64url")]
+    pub challenge: Vec<u8>,
+    pub timeout: u32,
+    pub rp_id: String,
+    pub user_verification: String,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AuthenticatorAttestationResponse {
+    #[serde(rename = "clientDataJSON", with = "b64url")]
+    pub client_data_json: Vec<u8>,
+    #[serde(with = "b64url")]
+    pub attestation_object: Vec<u8>,
+}
+
+/// JSON form (`PublicKeyCredential.toJSON()`) of the credential returned by `navigator.credentials.create()`
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RegisterPublicKeyCredential {
+    pub id: String,
+    #[serde(with = "b64url")]
+    pub raw_id: Vec<u8>,
+    #[serde(rename = "type")]
+    pub type_: String,
+    pub response: AuthenticatorAttestationResponse,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AuthenticatorAssertionResponse {
+    #[serde(rename = "clientDataJSON", with = "b64url")]
+    pub client_data_json: Vec<u8>,
+    #[serde(with = "b64url")]
+    pub authenticator_data: Vec<u8>,
+    #[serde(with = "b64url")]
+    pub signature: Vec<u8>,
+    #[serde(default, with = "optional_b64url")]
+    pub user_handle: Option<Vec<u8>>,
+}
+
+mod optional_b64url {
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(bytes: &Option<Vec<u8>>, serializer: S) -> Result<S::Ok, S::Error> {
+        match bytes {
+            Some(bytes) => super::b64url::serialize(bytes, serializer),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Vec<u8>>, D::Error> {
+        #[derive(Deserialize)]
+        struct Wrapper(#[serde(with = "super::b64url")] Vec<u8>);
+
+        Ok(Option::<Wrapper>::deserialize(deserializer)?.map(|Wrapper(bytes)| bytes))
+    }
+}
+
+/// JSON form (`PublicKeyCredential.toJSON()`) of the credential returned by `navigator.credentials.get()`
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PublicKeyCredential {
+    pub id: String,
+    #[serde(with = "b64url")]
+    pub raw_id: Vec<u8>,
+    #[serde(rename = "type")]
+    pub type_: String,
+    pub response: AuthenticatorAssertionResponse,
+}
+
+/// A credential that passed the registration ceremony, ready to be stored
+#[derive(Clone, Debug)]
+pub struct RegisteredCredential {
+    pub credential_id: Vec<u8>,
+    /// SEC1 uncompressed P-256 point
+    pub public_key: Vec<u8>,
+    pub sign_count: u32,
+}
+
+#[derive(Deserialize)]
+struct ClientData {
+    #[serde(rename = "type")]
+    type_: String,
+    challenge: String,
+    origin: String,
+}
+
+struct AuthenticatorData {
+    rp_id_hash: Vec<u8>,
+    flags: u8,
+    sign_count: u32,
+    attested_credential: Option<(Vec<u8>, Value)>,
+}
+
+impl AuthenticatorData {
+    fn parse(data: &[u8]) -> Result<Self, WebauthnError> {
+        if data.len() < 37 {
+            return Err(malformed("authenticator data is too short"));
+        }
+        let rp_id_hash = data[0..32].to_vec();
+        let flags = data[32];
+        let sign_count = u32::from_be_bytes([data[33], data[34], data[35], data[36]]);
+
+        let attested_credential = if flags & FLAG_ATTESTED_CREDENTIAL_DATA != 0 {
+            // aaguid (16 bytes), credential id length (2 bytes), credential id, COSE public key
+            let rest = data.get(37 + 16..).ok_or_else(|| malformed("attested credential data is too short"))?;
+            let id_length = rest.get(0..2)
+                .map(|l| u16::from_be_bytes([l[0], l[1]]) as usize)
+                .ok_or_else(|| malformed("attested credential data is too short"))?;
+            let credential_id = rest.get(2..2 + id_length)
+                .ok_or_else(|| malformed("credential id is truncated"))?
+                .to_vec();
+            let public_key: Value = ciborium::de::from_reader(&rest[2 + id_length..]).map_err(malformed)?;
+            Some((credential_id, public_key))
+        } else {
+            None
+        };
+
+        Ok(AuthenticatorData { rp_id_hash, flags, sign_count, attested_credential })
+    }
+}
+
+fn cose_lookup(map: &[(Value, Value)], key: i128) -> Option<&Value> {
+    map.iter()
+        .find(|(k, _)| matches!(k, Value::Integer(i) if i128::from(*i) == key))
+        .map(|(_, v)| v)
+}
+
+fn cose_int(map: &[(Value, Value)], key: i128) -> Option<i128> {
+    match cose_lookup(map, key) {
+        Some(Value::Integer(i)) => Some(i128::from(*i)),
+        _ => None,
+    }
+}
+
+fn cose_bytes(map: &[(Value, Value)], key: i128) -> Option<&[u8]> {
+    match cose_lookup(map, key) {
+        Some(Value::Bytes(b)) => Some(b.as_slice()),
+        _ => None,
+    }
+}
+
+/// Convert an EC2 / P-256 / ES256 COSE key to an SEC1 uncompressed point
+fn cose_to_sec1(key: &Value) -> Result<Vec<u8>, WebauthnError> {
+    let map = key.as_map().ok_or_else(|| malformed("credential public key is not a map"))?;
+
+    // kty = EC2 (2), alg = ES256 (-7), crv = P-256 (1)
+    if cose_int(map, 1) != Some(2) || cose_int(map, 3) != Some(COSE_ALG_ES256) || cose_int(map, -1) != Some(1) {
+        return Err(WebauthnError::UnsupportedKey);
+    }
+    let (x, y) = match (cose_bytes(map, -2), cose_bytes(map, -3)) {
+        (Some(x), Some(y)) if x.len() == 32 && y.len() == 32 => (x, y),
+        _ => return Err(WebauthnError::UnsupportedKey),
+    };
+
+    let mut point = Vec::with_capacity(65);
+    point.push(0x04);
+    point.extend_from_slice(x);
+    point.extend_from_slice(y);
+    VerifyingKey::from_sec1_bytes(&point).map_err(|_| WebauthnError::UnsupportedKey)?;
+    Ok(point)
+}
+
+#[derive(Clone, Debug)]
+pub struct Webauthn {
+    rp_id: String,
+    rp_name: String,
+    origin: String,
+}
+
+impl Webauthn {
+    pub fn new(rp_id: &str, rp_name: &str, origin: &str) -> Self {
+        Self {
+            rp_id: rp_id.to_string(),
+            rp_name: rp_name.to_string(),
+            origin: origin.to_string(),
+        }
+    }
+
+    /// The user handle we give to authenticators for this user
+    pub fn user_handle(user: &User) -> Vec<u8> {
+        user.id.to_be_bytes().to_vec()
+    }
+
+    pub fn start_registration(&self, user: &User, existing: &[WebauthnCredential]) -> (CreationOptions, Challenge) {
+        let challenge = Challenge::generate();
+        let options = CreationOptions {
+            rp: RelyingParty { id: self.rp_id.clone(), name: self.rp_name.clone() },
+            user: UserEntity {
+                id: Self::user_handle(user),
+                name: user.email.clone(),
+                display_name: user.email.clone(),
+            },
+            challenge: challenge.value.clone(),
+            pub_key_cred_params: vec![CredentialParameters {
+                type_: "public-key".to_string(),
+                alg: COSE_ALG_ES256 as i32,
+            }],
+            timeout: CEREMONY_TIMEOUT_MS,
+            attestation: "none".to_string(),
+            exclude_credentials: existing.iter()
+                .map(|c| CredentialDescriptor { type_: "public-key".to_string(), id: c.credential_id.clone() })
+                .collect(),
+            authenticator_selection: AuthenticatorSelection {
+                resident_key: "required".to_string(),
+                user_verification: "required".to_string(),
+            },
+        };
+        (options, challenge)
+    }
+
+    pub fn finish_registration(
+        &self,
+        challenge: &Challenge,
+        credential: &RegisterPublicKeyCredential,
+    ) -> Result<RegisteredCredential, WebauthnError> {
+        verify_credential_type(&credential.type_)?;
+        self.verify_client_data(&credential.response.client_data_json, "webauthn.create", challenge)?;
+
+        // Attestation statements are not verified as we request "none"
+        let attestation: Value = ciborium::de::from_reader(credential.response.attestation_object.as_slice())
+            .map_err(malformed)?;
+        let auth_data = attestation.as_map()
+            .and_then(|map| map.iter().find(|(k, _)| k.as_text() == Some("authData")))
+            .and_then(|(_, v)| v.as_bytes())
+            .ok_or_else(|| malformed("attestation object has no authData"))?;
+
+        let auth_data = AuthenticatorData::parse(auth_data)?;
+        self.verify_authenticator_data(&auth_data)?;
+
+        let (credential_id, public_key) = auth_data.attested_credential
+            .ok_or_else(|| malformed("authenticator data has no attested credential"))?;
+        if credential_id != credential.raw_id {
+            return Err(malformed("credential id does not match rawId"));
+        }
+
+        Ok(RegisteredCredential {
+            credential_id,
+            public_key: cose_to_sec1(&public_key)?,
+            sign_count: auth_data.sign_count,
+        })
+    }
+
+    pub fn start_authentication(&self) -> (RequestOptions, Challenge) {
+        let challenge = Challenge::generate();
+        let options = RequestOptions {
+            challenge: challenge.value.clone(),
+            timeout: CEREMONY_TIMEOUT_MS,
+            rp_id: self.rp_id.clone(),
+            user_verification: "required".to_string(),
+        };
+        (options, challenge)
+    }
+
+    /// Verify an assertion against the stored credential, returning the new signature counter
+    pub fn finish_authentication(
+        &self,
+        challenge: &Challenge,
+        stored: &WebauthnCredential,
+        credential: &PublicKeyCredential,
+    ) -> Result<u32, WebauthnError> {
+        verify_credential_type(&credential.type_)?;
+        let response = &credential.response;
+        self.verify_client_data(&response.client_data_json, "webauthn.get", challenge)?;
+
+        let auth_data = AuthenticatorData::parse(&response.authenticator_data)?;
+        self.verify_authenticator_data(&auth_data)?;
+
+        let key = VerifyingKey::from_sec1_bytes(&stored.public_key).map_err(|_| WebauthnError::UnsupportedKey)?;
+        let signature = Signature::from_der(&response.signature).map_err(|_| WebauthnError::InvalidSignature)?;
+        let mut signed_data = response.authenticator_data.clone();
+        signed_data.extend_from_slice(&Sha256::digest(&response.client_data_json));
+        key.verify(&signed_data, &signature).map_err(|_| WebauthnError::InvalidSignature)?;
+
+        // Authenticators that do not implement a counter always return 0
+        let stored_count = stored.sign_count as u32;
+        if (auth_data.sign_count != 0 || stored_count != 0) && auth_data.sign_count <= stored_count {
+            return Err(WebauthnError::CounterRegression);
+        }
+
+        Ok(auth_data.sign_count)
+    }
+
+    fn verify_client_data(&self, client_data_json: &[u8], expected_type: &str, challenge: &Challenge) -> Result<(), WebauthnError> {
+        let client_data: ClientData = serde_json::from_slice(client_data_json).map_err(malformed)?;
+
+        if client_data.type_ != expected_type {
+            return Err(WebauthnError::TypeMismatch);
+        }
+        if BASE64_URL.decode(&client_data.challenge).map_err(malformed)? != challenge.value {
+            return Err(WebauthnError::ChallengeMismatch);
+        }
+        if challenge.is_expired() {
+            return Err(WebauthnError::ChallengeExpired);
+        }
+        if client_data.origin != self.origin {
+            return Err(WebauthnError::OriginMismatch);
+        }
+        Ok(())
+    }
+
+    fn verify_authenticator_data(&self, auth_data: &AuthenticatorData) -> Result<(), WebauthnError> {
+        if auth_data.rp_id_hash != Sha256::digest(self.rp_id.as_bytes()).as_slice() {
+            return Err(WebauthnError::RpIdMismatch);
+        }
+        if auth_data.flags & FLAG_USER_PRESENT == 0 {
+            return Err(WebauthnError::UserNotPresent);
+        }
+        // a passkey stands for both factors only if the authenticator verified the user
+        if auth_data.flags & FLAG_USER_VERIFIED == 0 {
+            return Err(WebauthnError::UserNotVerified);
+        }
+        Ok(())
+    }
+}
+
+fn verify_credential_type(type_: &str) -> Result<(), WebauthnError> {
+    match type_ {
+        "public-key" => Ok(()),
+        _ => Err(WebauthnError::CredentialTypeMismatch),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::soft_authenticator::SoftAuthenticator;
+
+    const RP_ID: &str = "localhost";
+    const ORIGIN: &str = "http://localhost:7878";
+
+    fn webauthn() -> Webauthn {
+        Webauthn::new(RP_ID, "Anthère", ORIGIN)
+    }
+
+    fn user() -> User {
+        let now = chrono::Utc::now().naive_utc();
+        User {
+            id: 42,
+            email: "miles.davis@trumpet.com".to_string(),
+            password: String::new(),
+            reset_password_token: None,
+            reset_password_sent_at: None,
+            sign_in_count: 0,
+            current_sign_in_at: None,
+            last_sign_in_at: None,
+            current_sign_in_ip: None,
+            last_sign_in_ip: None,
+            confirmation_token: None,
+            confirmation_sent_at: None,
+            confirmed_at: None,
+            created_at: now,
+            updated_at: now,
+            role: "user".to_string(),
+            locked_at: None,
+        }
+    }
+
+    fn stored(registered: RegisteredCredential) -> WebauthnCredential {
+        let now = chrono::Utc::now().naive_utc();
+        WebauthnCredential {
+            id: 1,
+            user_id: 42,
+            credential_id: registered.credential_id,
+            public_key: registered.public_key,
+            sign_count: registered.sign_count as i64,
+            name: "test".to_string(),
+            last_used_at: None,
+            created_at: now,
+            updated_at: now,
+        }
+    }
+
+    #[test]
+    fn test_register_and_authenticate() {
+        let webauthn = webauthn();
+        let mut authenticator = SoftAuthenticator::new(RP_ID, ORIGIN);
+
+        let (options, challenge) = webauthn.start_registration(&user(), &[]);
+        let credential = authenticator.register(&options);
+        let registered = webauthn.finish_registration(&challenge, &credential).unwrap();
+        assert_eq!(registered.credential_id, authenticator.credential_id());
+
+        let (options, challenge) = webauthn.start_authentication();
+        let assertion = authenticator.authenticate(&options);
+        let count = webauthn.finish_authentication(&challenge, &stored(registered), &assertion).unwrap();
+        assert_eq!(count, 1);
+    }
+
+    #[test]
+    fn test_register_wrong_challenge() {
+        let webauthn = webauthn();
+        let mut authenticator = SoftAuthenticator::new(RP_ID, ORIGIN);
+
+        let (options, _) = webauthn.start_registration(&user(), &[]);
+        let (_, other_challenge) = webauthn.start_registration(&user(), &[]);
+        let credential = authenticator.register(&options);
+        let res = webauthn.finish_registration(&other_challenge, &credential);
+        assert_eq!(res.unwrap_err(), WebauthnError::ChallengeMismatch);
+    }
+
+    #[test]
+    fn test_authenticate_wrong_origin() {
+        let webauthn = webauthn();
+        let mut authenticator = SoftAuthenticator::new(RP_ID, ORIGIN);
+        let (options, challenge) = webauthn.start_registration(&user(), &[]);
+        let registered = webauthn.finish_registration(&challenge, &authenticator.register(&options)).unwrap();
+
+        let mut phishing = authenticator.with_origin("https://evil.example.com");
+        let (options, challenge) = webauthn.start_authentication();
+        let assertion = phishing.authenticate(&options);
+        let res = webauthn.finish_authentication(&challenge, &stored(registered), &assertion);
+        assert_eq!(res.unwrap_err(), WebauthnError::OriginMismatch);
+    }
+
+    #[test]
+    fn test_authenticate_tampered_signature() {
+        let webauthn = webauthn();
+        let mut authenticator = SoftAuthenticator::new(RP_ID, ORIGIN);
+        let (options, challenge) = webauthn.start_registration(&user(), &[]);
+        let registered = webauthn.finish_registration(&challenge, &authenticator.register(&options)).unwrap();
+
+        let (options, challenge) = webauthn.start_authentication();
+        let mut assertion = authenticator.authenticate(&options);
+        // flip the backup eligibility flag after signing
+        assertion.response.authenticator_data[32] ^= 0x08;
+        let res = webauthn.finish_authentication(&challenge, &stored(registered), &assertion);
+        assert_eq!(res.unwrap_err(), WebauthnError::InvalidSignature);
+    }
+
+    #[test]
+    fn test_authenticate_counter_regression() {
+        let webauthn = webauthn();
+        let mut authenticator = SoftAuthenticator::new(RP_ID, ORIGIN);
+        let (options, challenge) = webauthn.start_registration(&user(), &[]);
+        let registered = webauthn.finish_registration(&challenge, &authenticator.register(&options)).unwrap();
+        let mut stored = stored(registered);
+        stored.sign_count = 10;
+
+        let (options, challenge) = webauthn.start_authentication();
+        let assertion = authenticator.authenticate(&options);
+        let res = webauthn.finish_authentication(&challenge, &stored, &assertion);
+        assert_eq!(res.unwrap_err(), WebauthnError::CounterRegression);
+    }
+
+    #[test]
+    fn test_user_not_verified() {
+        let webauthn = webauthn();
+        let mut authenticator = SoftAuthenticator::new(RP_ID, ORIGIN);
+        let (options, challenge) = webauthn.start_registration(&user(), &[]);
+        assert_eq!(options.authenticator_selection.user_verification, "required");
+        let registered = webauthn.finish_registration(&challenge, &authenticator.register(&options)).unwrap();
+
+        // only touched, e.g. a security key without a PIN
+        let mut unverified = authenticator.without_user_verification();
+        let (options, challenge) = webauthn.start_authentication();
+        assert_eq!(options.user_verification, "required");
+        let assertion = unverified.authenticate(&options);
+        let res = webauthn.finish_authentication(&challenge, &stored(registered), &assertion);
+        assert_eq!(res.unwrap_err(), WebauthnError::UserNotVerified);
+
+        let mut unverified = SoftAuthenticator::new(RP_ID, ORIGIN).without_user_verification();
+        let (options, challenge) = webauthn.start_registration(&user(), &[]);
+        let res = webauthn.finish_registration(&challenge, &unverified.register(&options));
+        assert_eq!(res.unwrap_err(), WebauthnError::UserNotVerified);
+    }
+
+    #[test]
+    fn test_credential_type() {
+        let webauthn = webauthn();
+        let mut authenticator = SoftAuthenticator::new(RP_ID, ORIGIN);
+        let (options, challenge) = webauthn.start_registration(&user(), &[]);
+        let mut credential = authenticator.register(&options);
+        credential.type_ = "password".to_string();
+        assert_eq!(webauthn.finish_registration(&challenge, &credential).unwrap_err(), WebauthnError::CredentialTypeMismatch);
+
+        credential.type_ = "public-key".to_string();
+        let registered = webauthn.finish_registration(&challenge, &credential).unwrap();
+        let (options, challenge) = webauthn.start_authentication();
+        let mut assertion = authenticator.authenticate(&options);
+        assertion.type_ = "password".to_string();
+        let res = webauthn.finish_authentication(&challenge, &stored(registered), &assertion);
+        assert_eq!(res.unwrap_err(), WebauthnError::CredentialTypeMismatch);
+    }
+
+    #[test]
+    fn test_challenge_expired() {
+        let webauthn = webauthn();
+        let mut authenticator = SoftAuthenticator::new(RP_ID, ORIGIN);
+        let (options, mut challenge) = webauthn.start_registration(&user(), &[]);
+        let credential = authenticator.register(&options);
+        challenge.issued_at -= i64::from(CEREMONY_TIMEOUT_MS) + 1;
+        assert_eq!(webauthn.finish_registration(&challenge, &credential).unwrap_err(), WebauthnError::ChallengeExpired);
+
+        let (options, challenge) = webauthn.start_registration(&user(), &[]);
+        let registered = webauthn.finish_registration(&challenge, &authenticator.register(&options)).unwrap();
+        let (options, mut challenge) = webauthn.start_authentication();
+        let assertion = authenticator.authenticate(&options);
+        challenge.issued_at -= i64::from(CEREMONY_TIMEOUT_MS) + 1;
+        let res = webauthn.finish_authentication(&challenge, &stored(registered), &assertion);
+        assert_eq!(res.unwrap_err(), WebauthnError::ChallengeExpired);
+    }
+}
@@ -0,0 +1,130 @@
+//! A software authenticator producing genuine ES256 WebAuthn responses, used to test the ceremonies.
+
+use ciborium::Value;
+use p256::ecdsa::{DerSignature, SigningKey, signature::Signer};
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+use super::{
+    AuthenticatorAssertionResponse, AuthenticatorAttestationResponse, BASE64_URL,
+    CreationOptions, PublicKeyCredential, RegisterPublicKeyCredential, RequestOptions,
+};
+use base64::Engine;
+
+#[derive(Clone)]
+pub struct SoftAuthenticator {
+    key: SigningKey,
+    credential_id: Vec<u8>,
+    user_handle: Vec<u8>,
+    rp_id: String,
+    origin: String,
+    sign_count: u32,
+    /// Whether the user is verified, e.g. by a PIN or biometrics, or only touches the authenticator
+    user_verified: bool,
+}
+
+impl SoftAuthenticator {
+    pub fn new(rp_id: &str, origin: &str) -> Self {
+        let mut credential_id = vec![0u8; 16];
+        rand::rngs::OsRng.fill_bytes(&mut credential_id);
+        Self {
+            key: SigningKey::random(&mut rand::rngs::OsRng),
+            credential_id,
+            user_handle: Vec::new(),
+            rp_id: rp_id.to_string(),
+            origin: origin.to_string(),
+            sign_count: 0,
+            user_verified: true,
+        }
+    }
+
+    /// The same authenticator, used from another origin (e.g. a phishing site)
+    pub fn with_origin(&self, origin: &str) -> Self {
+        Self { origin: origin.to_string(), ..self.clone() }
+    }
+
+    /// The same authenticator, without verifying the user
+    pub fn without_user_verification(&self) -> Self {
+        Self { user_verified: false, ..self.clone() }
+    }
+
+    pub fn credential_id(&self) -> Vec<u8> {
+        self.credential_id.clone()
+    }
+
+    pub fn register(&mut self, options: &CreationOptions) -> RegisterPublicKeyCredential {
+        self.user_handle = options.user.id.clone();
+
+        let point = self.key.verifying_key().to_encoded_point(false);
+        let cose_key = Value::Map(vec![
+            (Value::Integer(1.into()), Value::Integer(2.into())),
+            (Value::Integer(3.into()), Value::Integer((-7).into())),
+            (Value::Integer((-1).into()), Value::Integer(1.into())),
+            (Value::Integer((-2).into()), Value::Bytes(point.x().unwrap().to_vec())),
+            (Value::Integer((-3).into()), Value::Bytes(point.y().unwrap().to_vec())),
+        ]);
+
+        let mut auth_data = self.authenticator_data(0x40);
+        auth_data.extend_from_slice(&[0u8; 16]);
+        auth_data.extend_from_slice(&(self.credential_id.len() as u16).to_be_bytes());
+        auth_data.extend_from_slice(&self.credential_id);
+        ciborium::ser::into_writer(&cose_key, &mut auth_data).unwrap();
+
+        let attestation = Value::Map(vec![
+            (Value::Text("fmt".to_string()), Value::Text("none".to_string())),
+            (Value::Text("attStmt".to_string()), Value::Map(vec![])),
+            (Value::Text("authData".to_string()), Value::Bytes(auth_data)),
+        ]);
+        let mut attestation_object = Vec::new();
+        ciborium::ser::into_writer(&attestation, &mut attestation_object).unwrap();
+
+        RegisterPublicKeyCredential {
+            id: BASE64_URL.encode(&self.credential_id),
+            raw_id: self.credential_id.clone(),
+            type_: "public-key".to_string(),
+            response: AuthenticatorAttestationResponse {
+                client_data_json: self.client_data("webauthn.create", &options.challenge),
+                attestation_object,
+            },
+        }
+    }
+
+    pub fn authenticate(&mut self, options: &RequestOptions) -> PublicKeyCredential {
+        self.sign_count += 1;
+        let authenticator_data = self.authenticator_data(0);
+        let client_data_json = self.client_data("webauthn.get", &options.challenge);
+
+        let mut signed_data = authenticator_data.clone();
+        signed_data.extend_from_slice(&Sha256::digest(&client_data_json));
+        let signature: DerSignature = self.key.sign(&signed_data);
+
+        PublicKeyCredential {
+            id: BASE64_URL.encode(&self.credential_id),
+            raw_id: self.credential_id.clone(),
+            type_: "public-key".to_string(),
+            response: AuthenticatorAssertionResponse {
+                client_data_json,
+                authenticator_data,
+                signature: signature.as_bytes().to_vec(),
+                user_handle: Some(self.user_handle.clone()),
+            },
+        }
+    }
+
+    fn authenticator_data(&self, flags: u8) -> Vec<u8> {
+        // user present, and verified
+        let flags = flags | 0x01 | if self.user_verified { 0x04 } else { 0 };
+        let mut data = Sha256::digest(self.rp_id.as_bytes()).to_vec();
+        data.push(flags);
+        data.extend_from_slice(&self.sign_count.to_be_bytes());
+        data
+    }
+
+    fn client_data(&self, type_: &str, challenge: &[u8]) -> Vec<u8> {
+        serde_json::to_vec(&serde_json::json!({
+            "type": type_,
+            "challenge": BASE64_URL.encode(challenge),
+            "origin": self.origin,
+            "crossOrigin": false,
+        })).unwrap()
+    }
+}